@@ -0,0 +1,69 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// broadcast of a frame to 50 websocket clients, each receiver cloning the message as BroadcastStream does.
+// The frame data was a Vec<u8> copied for every client, it is now a Bytes shared by all of them.
+// run with: cargo bench --bench fanout
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::json;
+use tokio::sync::broadcast;
+
+const SUBSCRIBERS: usize = 50;
+
+// DataFrame before and after the Bytes change
+#[derive(Clone)]
+struct VecFrame {
+    #[allow(dead_code)]
+    metadata: serde_json::Value,
+    data: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct BytesFrame {
+    #[allow(dead_code)]
+    metadata: serde_json::Value,
+    data: Bytes,
+}
+
+fn metadata() -> serde_json::Value {
+    json!({ "media": "video", "type": "keyframe", "ts": 3600000.0, "codec": "avc1.42c01e", "seq": 1 })
+}
+
+// sends a frame and lets every subscriber receive its own clone
+fn fan_out<T: Clone>(tx: &broadcast::Sender<T>, rxs: &mut [broadcast::Receiver<T>], frame: T, len: impl Fn(&T) -> usize) -> usize {
+    tx.send(frame).ok();
+    rxs.iter_mut().map(|rx| len(&rx.try_recv().unwrap())).sum()
+}
+
+fn fanout(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout_50_subscribers");
+    // P frame and keyframe of a 1080p stream
+    for size in [20_000, 200_000] {
+        let payload = vec![0x41u8; size];
+        group.throughput(Throughput::Bytes((size * SUBSCRIBERS) as u64));
+
+        let (tx, _) = broadcast::channel::<VecFrame>(16);
+        let mut rxs: Vec<_> = (0..SUBSCRIBERS).map(|_| tx.subscribe()).collect();
+        group.bench_with_input(BenchmarkId::new("vec", size), &payload, |b, payload| {
+            b.iter(|| fan_out(&tx, &mut rxs, VecFrame { metadata: metadata(), data: payload.clone() }, |frame| frame.data.len()))
+        });
+
+        let (tx, _) = broadcast::channel::<BytesFrame>(16);
+        let mut rxs: Vec<_> = (0..SUBSCRIBERS).map(|_| tx.subscribe()).collect();
+        group.bench_with_input(BenchmarkId::new("bytes", size), &payload, |b, payload| {
+            b.iter(|| fan_out(&tx, &mut rxs, BytesFrame { metadata: metadata(), data: Bytes::copy_from_slice(payload) }, |frame| frame.data.len()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -0,0 +1,31 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// with the embed-www feature, the directory embedded as player assets: RTSP2WEB_EMBED_WWW or ./www,
+// the build fails without index.html in it rather than producing a binary without player
+
+use std::path::PathBuf;
+
+fn main() {
+    if std::env::var_os("CARGO_FEATURE_EMBED_WWW").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-env-changed=RTSP2WEB_EMBED_WWW");
+    let dir = match std::env::var_os("RTSP2WEB_EMBED_WWW") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("www"),
+    };
+    println!("cargo:rerun-if-changed={}", dir.display());
+    if !dir.join("index.html").is_file() {
+        eprintln!("error: the embed-www feature needs a player, {} has no index.html", dir.display());
+        eprintln!("deploy the player in ./www or set RTSP2WEB_EMBED_WWW to its directory, or build without embed-www");
+        std::process::exit(1);
+    }
+    println!("cargo:rustc-env=RTSP2WEB_EMBED_DIR={}", dir.canonicalize().unwrap().display());
+}
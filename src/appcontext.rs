@@ -9,15 +9,55 @@
 
 
 use std::{collections::HashMap, sync::{Arc, Mutex}};
+use crate::memory::MemoryBudget;
 use crate::streamdef::StreamsDef;
+use crate::websocketservice::OutputFormat;
 
 pub struct AppContext {
     pub streams: HashMap<String,Arc<Mutex<StreamsDef>>>,
+    pub ws_all: bool,
+    // the cameras are not connected and the websockets not served
+    pub control_only: bool,
+    pub routes: Routes,
+    pub output_format: OutputFormat,
+    pub reject_paused: bool,
+    // bearer token of the configuration api, refused when not set
+    pub api_token: Option<String>,
+    pub ws_max_frame_size: usize,
+    pub memory: Option<Arc<MemoryBudget>>,
+}
+
+// configurable route paths
+#[derive(Clone)]
+pub struct Routes {
+    // prepended to all the routes
+    pub base: String,
+    // prepended to the stream names to get the websocket paths
+    pub ws: String,
+    pub streams: String,
+    pub version: String,
+}
+
+impl Routes {
+    pub fn websocket(&self, key: &str) -> String {
+        format!("{}{}", self.ws, key)
+    }
+
+    // path as requested by the clients
+    pub fn public(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Self { base: String::new(), ws: String::new(), streams: "/api/streams".to_string(), version: "/api/version".to_string() }
+    }
 }
 
 impl AppContext {
     pub fn new(streams: HashMap<String,Arc<Mutex<StreamsDef>>>) -> Self {
-        Self { streams }
+        Self { streams, ws_all: false, control_only: false, routes: Routes::default(), output_format: OutputFormat::Json, reject_paused: false, api_token: None, ws_max_frame_size: 65536, memory: None }
     }
 }
 
@@ -25,6 +65,14 @@ impl Clone for AppContext {
     fn clone(&self) -> Self {
         Self {
             streams: self.streams.clone(),
+            ws_all: self.ws_all,
+            control_only: self.control_only,
+            routes: self.routes.clone(),
+            output_format: self.output_format,
+            reject_paused: self.reject_paused,
+            api_token: self.api_token.clone(),
+            ws_max_frame_size: self.ws_max_frame_size,
+            memory: self.memory.clone(),
         }
     }
 }
@@ -0,0 +1,33 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// player assets embedded at build time with the embed-www feature (see build.rs), served when ./www
+// has no index.html so a single binary comes with its player
+
+use actix_web::{HttpRequest, HttpResponse};
+use include_dir::{include_dir, Dir};
+
+static WWW: Dir = include_dir!("$RTSP2WEB_EMBED_DIR");
+
+pub fn has_index() -> bool {
+    WWW.get_file("index.html").is_some()
+}
+
+// GET /{path}, the path relative to the base path
+pub async fn serve(req: HttpRequest) -> HttpResponse {
+    let path = req.match_info().query("path").trim_start_matches('/');
+    let path = if path.is_empty() || path.ends_with('/') { format!("{}index.html", path) } else { path.to_string() };
+    match WWW.get_file(&path) {
+        Some(file) => {
+            let ext = path.rsplit('.').next().unwrap_or_default();
+            HttpResponse::Ok().content_type(actix_files::file_extension_to_mime(ext)).body(file.contents())
+        },
+        None => HttpResponse::NotFound().finish(),
+    }
+}
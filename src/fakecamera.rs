@@ -0,0 +1,128 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// RTSP server of the tests: a single H.264 track sent over interleaved TCP, one RTP packet per NAL unit, with
+// an optional Basic authentication and redirect of the DESCRIBE
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const SPROP: &str = "Z0LAHtoFB+Q=,aM48gA==";
+const SSRC: u32 = 0x1234_5678;
+
+pub struct Packet {
+    pub seq: u16,
+    pub ts: u32,
+    pub nal: Vec<u8>,
+}
+
+impl Packet {
+    pub fn keyframe(seq: u16, ts: u32) -> Self {
+        Packet { seq, ts, nal: vec![0x65, 0x88, 0x84, 0x21, 0xa0] }
+    }
+
+    pub fn frame(seq: u16, ts: u32) -> Self {
+        Packet { seq, ts, nal: vec![0x41, 0x9a, 0x24, 0x6c, 0x42] }
+    }
+}
+
+#[derive(Default)]
+pub struct FakeCamera {
+    // "user:password" required by a Basic challenge
+    pub credentials: Option<String>,
+    // answered with a 302 to the DESCRIBE
+    pub redirect: Option<String>,
+    pub packets: Vec<Packet>,
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    data.chunks(3).flat_map(|chunk| {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        (0..4).map(move |i| match i <= chunk.len() {
+            true => ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char,
+            false => '=',
+        })
+    }).collect()
+}
+
+impl FakeCamera {
+    // listens on a local port, the url of the stream is returned
+    pub async fn start(self) -> url::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("rtsp://{}/stream", listener.local_addr().unwrap())).unwrap();
+        let camera = std::sync::Arc::new(self);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(camera.clone().serve(socket));
+            }
+        });
+        url
+    }
+
+    async fn serve(self: std::sync::Arc<Self>, mut socket: TcpStream) {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 4096];
+        loop {
+            let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                match socket.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                }
+                continue;
+            };
+            let request = String::from_utf8_lossy(&buf[..end]).to_string();
+            buf.drain(..end + 4);
+            let mut lines = request.lines();
+            let mut start = lines.next().unwrap_or_default().split(' ');
+            let (method, uri) = (start.next().unwrap_or_default(), start.next().unwrap_or_default());
+            let headers: Vec<(String, String)> = lines.filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+                .collect();
+            let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str());
+            let cseq = header("cseq").unwrap_or("0");
+
+            let response = match method {
+                "DESCRIBE" if self.credentials.as_ref().is_some_and(|creds| header("authorization") != Some(&format!("Basic {}", base64(creds.as_bytes())))) =>
+                    format!("RTSP/1.0 401 Unauthorized\r\nCSeq: {}\r\nWWW-Authenticate: Basic realm=\"camera\"\r\n\r\n", cseq),
+                "DESCRIBE" if self.redirect.is_some() =>
+                    format!("RTSP/1.0 302 Moved Temporarily\r\nCSeq: {}\r\nLocation: {}\r\n\r\n", cseq, self.redirect.as_deref().unwrap()),
+                "DESCRIBE" => {
+                    let sdp = format!("v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=test\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\n\
+                        m=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\na=fmtp:96 packetization-mode=1;sprop-parameter-sets={}\r\na=control:track0\r\n", SPROP);
+                    format!("RTSP/1.0 200 OK\r\nCSeq: {}\r\nContent-Base: {}/\r\nContent-Type: application/sdp\r\nContent-Length: {}\r\n\r\n{}", cseq, uri, sdp.len(), sdp)
+                },
+                "SETUP" => format!("RTSP/1.0 200 OK\r\nCSeq: {}\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1;ssrc={:08X}\r\nSession: 1\r\n\r\n", cseq, SSRC),
+                "PLAY" => {
+                    let first = self.packets.first().map_or((0, 0), |packet| (packet.seq, packet.ts));
+                    format!("RTSP/1.0 200 OK\r\nCSeq: {}\r\nSession: 1\r\nRTP-Info: url={}/track0;seq={};rtptime={}\r\n\r\n", cseq, uri.trim_end_matches('/'), first.0, first.1)
+                },
+                _ => format!("RTSP/1.0 200 OK\r\nCSeq: {}\r\nSession: 1\r\n\r\n", cseq),
+            };
+            if socket.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+            if method == "PLAY" {
+                for packet in &self.packets {
+                    let mut rtp = vec![0x80, 96 | 0x80];
+                    rtp.extend_from_slice(&packet.seq.to_be_bytes());
+                    rtp.extend_from_slice(&packet.ts.to_be_bytes());
+                    rtp.extend_from_slice(&SSRC.to_be_bytes());
+                    rtp.extend_from_slice(&packet.nal);
+                    let mut interleaved = vec![b'$', 0];
+                    interleaved.extend_from_slice(&(rtp.len() as u16).to_be_bytes());
+                    interleaved.extend_from_slice(&rtp);
+                    if socket.write_all(&interleaved).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
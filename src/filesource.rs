@@ -0,0 +1,194 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// H.264 Annex-B elementary stream read from a file: or a pipe, split in access units.
+// Containers like mp4 are not demuxed, convert them with: ffmpeg -i in.mp4 -c copy -bsf h264_mp4toannexb -f h264 out.h264
+
+use anyhow::{anyhow, Error};
+use h264_reader::nal::sps::SeqParameterSet;
+use h264_reader::nal::{Nal, RefNal};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::rtspclient::VideoSample;
+
+pub const CLOCK_RATE: u32 = 90000;
+const READ_SIZE: usize = 64 * 1024;
+
+pub struct FileFrame {
+    pub timestamp: i64,
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+    pub new_parameters: bool,
+}
+
+impl VideoSample for FileFrame {
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+    fn is_random_access_point(&self) -> bool {
+        self.keyframe
+    }
+    fn has_new_parameters(&self) -> bool {
+        self.new_parameters
+    }
+}
+
+// position of the next 00 00 01 start code
+fn find_start_code(data: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?.windows(3).position(|w| w == [0, 0, 1]).map(|pos| from + pos)
+}
+
+struct NalReader {
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl NalReader {
+    async fn next_nal(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            match find_start_code(&self.buf, 0) {
+                Some(first) => {
+                    let payload = first + 3;
+                    let end = match find_start_code(&self.buf, payload) {
+                        Some(next) => Some(next),
+                        None if self.eof => Some(self.buf.len()),
+                        None => None,
+                    };
+                    if let Some(next) = end {
+                        // trailing zeros belong to the next 4 bytes start code
+                        let mut end = next;
+                        while end > payload && self.buf[end - 1] == 0 {
+                            end -= 1;
+                        }
+                        let nal = self.buf[payload..end].to_vec();
+                        self.buf.drain(..next);
+                        return Ok(Some(nal));
+                    }
+                },
+                None if self.eof => return Ok(None),
+                None => {},
+            }
+            let start = self.buf.len();
+            self.buf.resize(start + READ_SIZE, 0);
+            let n = self.reader.read(&mut self.buf[start..]).await?;
+            self.buf.truncate(start + n);
+            self.eof = n == 0;
+        }
+    }
+}
+
+pub struct FileSource {
+    nals: NalReader,
+    pending: Option<Vec<u8>>,
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+    count: u64,
+    fps: u32,
+}
+
+impl FileSource {
+    pub async fn open(path: &str, fps: u32) -> Result<Self, Error> {
+        let file = tokio::fs::File::open(path).await.map_err(|e| anyhow!("{}: {}", path, e))?;
+        Ok(Self {
+            nals: NalReader { reader: Box::new(file), buf: vec![], eof: false },
+            pending: None,
+            sps: vec![],
+            pps: vec![],
+            count: 0,
+            fps: fps.max(1),
+        })
+    }
+
+    // read the file again, keeping the timestamps increasing
+    pub async fn reopen(&mut self, path: &str) -> Result<(), Error> {
+        let file = tokio::fs::File::open(path).await.map_err(|e| anyhow!("{}: {}", path, e))?;
+        self.nals = NalReader { reader: Box::new(file), buf: vec![], eof: false };
+        self.pending = None;
+        Ok(())
+    }
+
+    // parameter sets in Annex-B, as extract_config returns them
+    pub fn config(&self) -> Vec<u8> {
+        let mut cfg = vec![];
+        for nal in [&self.sps, &self.pps] {
+            cfg.extend_from_slice(&[0, 0, 0, 1]);
+            cfg.extend_from_slice(nal);
+        }
+        cfg
+    }
+
+    pub fn codec(&self) -> String {
+        match self.sps.get(1..4) {
+            Some(p) => format!("avc1.{:02x}{:02x}{:02x}", p[0], p[1], p[2]),
+            None => "avc1".to_string(),
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        SeqParameterSet::from_bits(RefNal::new(&self.sps, &[], true).rbsp_bits())
+            .ok()
+            .and_then(|sps| sps.pixel_dimensions().ok())
+            .unwrap_or_default()
+    }
+
+    // next access unit without its parameter sets, None at the end of the file
+    pub async fn next_frame(&mut self) -> Result<Option<FileFrame>, Error> {
+        let mut data = vec![];
+        let mut has_slice = false;
+        let mut keyframe = false;
+        let mut new_parameters = false;
+        loop {
+            let nal = match self.pending.take() {
+                Some(nal) => nal,
+                None => match self.nals.next_nal().await? {
+                    Some(nal) => nal,
+                    None => break,
+                },
+            };
+            if nal.is_empty() {
+                continue;
+            }
+            let nal_type = nal[0] & 0x1f;
+            // first_mb_in_slice 0 starts a new picture
+            let first_slice = (1..=5).contains(&nal_type) && nal.len() > 1 && nal[1] & 0x80 != 0;
+            if has_slice && (first_slice || (6..=9).contains(&nal_type)) {
+                self.pending = Some(nal);
+                break;
+            }
+            match nal_type {
+                7 => {
+                    new_parameters |= !self.sps.is_empty() && self.sps != nal;
+                    self.sps = nal;
+                },
+                8 => {
+                    new_parameters |= !self.pps.is_empty() && self.pps != nal;
+                    self.pps = nal;
+                },
+                9 => {},
+                _ => {
+                    has_slice |= (1..=5).contains(&nal_type);
+                    keyframe |= nal_type == 5;
+                    data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    data.extend_from_slice(&nal);
+                },
+            }
+        }
+        if !has_slice {
+            return Ok(None);
+        }
+
+        let timestamp = (self.count * (CLOCK_RATE / self.fps) as u64) as i64;
+        self.count += 1;
+        Ok(Some(FileFrame { timestamp, data, keyframe, new_parameters }))
+    }
+}
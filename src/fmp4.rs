@@ -0,0 +1,302 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// minimal fragmented MP4 muxer for a single H.264 track, as expected by Media Source Extensions:
+// an init segment (ftyp, moov) on the first keyframe and when the parameter sets change, then a
+// fragment (moof, mdat) per frame
+
+use std::time::Instant;
+
+use h264_reader::nal::sps::SeqParameterSet;
+use h264_reader::nal::{Nal, RefNal};
+
+use crate::paramsets::nal_units;
+use crate::streamdef::DataFrame;
+
+const TIMESCALE: u32 = 90000;
+const TRACK_ID: u32 = 1;
+// duration of the first fragment, the next ones last until the following frame
+const DEFAULT_DURATION: u32 = TIMESCALE / 25;
+const SAMPLE_SYNC: u32 = 0x0200_0000;
+const SAMPLE_NON_SYNC: u32 = 0x0101_0000;
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn mp4box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(8 + content.len());
+    b.extend_from_slice(&(8 + content.len() as u32).to_be_bytes());
+    b.extend_from_slice(kind);
+    b.extend_from_slice(content);
+    b
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, content: &[u8]) -> Vec<u8> {
+    let mut header = (flags & 0x00ff_ffff).to_be_bytes();
+    header[0] = version;
+    mp4box(kind, &[&header[..], content].concat())
+}
+
+fn u16s(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+pub fn dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    SeqParameterSet::from_bits(RefNal::new(sps, &[], true).rbsp_bits()).ok()?.pixel_dimensions().ok()
+}
+
+// AVCDecoderConfigurationRecord of a SPS and PPS, with 4 bytes NAL unit lengths
+pub fn avc_config(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    [
+        &[1, sps[1], sps[2], sps[3], 0xff, 0xe1][..],
+        &(sps.len() as u16).to_be_bytes(), sps,
+        &[1], &(pps.len() as u16).to_be_bytes(), pps,
+    ].concat()
+}
+
+// parameter sets are in the init segment, the length prefixed NAL units form the sample
+pub fn sample(nals: &[&[u8]]) -> Vec<u8> {
+    nals.iter()
+        .filter(|nal| !matches!(nal[0] & 0x1f, 7..=9))
+        .flat_map(|nal| (nal.len() as u32).to_be_bytes().into_iter().chain(nal.iter().copied()))
+        .collect()
+}
+
+fn init_segment(sps: &[u8], pps: &[u8]) -> Option<Vec<u8>> {
+    let (width, height) = dimensions(sps)?;
+    let ftyp = mp4box(b"ftyp", b"iso5\0\0\x02\0iso5iso6avc1mp41");
+
+    let mvhd = full_box(b"mvhd", 0, 0, &[
+        u32s(&[0, 0, 1000, 0, 0x0001_0000]),
+        u16s(&[0x0100, 0]),
+        u32s(&[0, 0]),
+        u32s(&MATRIX),
+        vec![0; 24],
+        u32s(&[TRACK_ID + 1]),
+    ].concat());
+    let tkhd = full_box(b"tkhd", 0, 3, &[
+        u32s(&[0, 0, TRACK_ID, 0, 0, 0, 0]),
+        u16s(&[0, 0, 0, 0]),
+        u32s(&MATRIX),
+        u32s(&[width << 16, height << 16]),
+    ].concat());
+    let mdhd = full_box(b"mdhd", 0, 0, &[u32s(&[0, 0, TIMESCALE, 0]), u16s(&[0x55c4, 0])].concat());
+    let hdlr = full_box(b"hdlr", 0, 0, &[&u32s(&[0])[..], b"vide", &[0; 12], b"VideoHandler\0"].concat());
+
+    let avcc = mp4box(b"avcC", &avc_config(sps, pps));
+    let avc1 = mp4box(b"avc1", &[
+        vec![0; 6],
+        u16s(&[1, 0, 0]),
+        vec![0; 12],
+        u16s(&[width as u16, height as u16]),
+        u32s(&[0x0048_0000, 0x0048_0000, 0]),
+        u16s(&[1]),
+        vec![0; 32],
+        u16s(&[0x0018, 0xffff]),
+        avcc,
+    ].concat());
+    let stbl = mp4box(b"stbl", &[
+        full_box(b"stsd", 0, 0, &[u32s(&[1]), avc1].concat()),
+        full_box(b"stts", 0, 0, &u32s(&[0])),
+        full_box(b"stsc", 0, 0, &u32s(&[0])),
+        full_box(b"stsz", 0, 0, &u32s(&[0, 0])),
+        full_box(b"stco", 0, 0, &u32s(&[0])),
+    ].concat());
+    let dinf = mp4box(b"dinf", &full_box(b"dref", 0, 0, &[u32s(&[1]), full_box(b"url ", 0, 1, &[])].concat()));
+    let minf = mp4box(b"minf", &[full_box(b"vmhd", 0, 1, &[0; 8]), dinf, stbl].concat());
+    let mdia = mp4box(b"mdia", &[mdhd, hdlr, minf].concat());
+    let trak = mp4box(b"trak", &[tkhd, mdia].concat());
+    let mvex = mp4box(b"mvex", &full_box(b"trex", 0, 0, &u32s(&[TRACK_ID, 1, 0, 0, 0])));
+    let moov = mp4box(b"moov", &[mvhd, trak, mvex].concat());
+    Some([ftyp, moov].concat())
+}
+
+#[derive(Default)]
+pub struct Muxer {
+    // parameter sets of the last init segment
+    params: Option<(Vec<u8>, Vec<u8>)>,
+    sequence: u32,
+    first_ts: Option<u64>,
+    last: Option<(u64, u32)>,
+    clock: Option<Instant>,
+}
+
+impl Muxer {
+    // after a reset init message the player recreates its SourceBuffer, that gets an init segment at the next keyframe
+    pub fn reset(&mut self, frame: &DataFrame) -> bool {
+        let reset = frame.metadata["type"] == "init" && frame.metadata["reset"] == true && self.params.is_some();
+        if reset {
+            self.params = None;
+        }
+        reset
+    }
+
+    // init segment when needed and fragment of a broadcasted frame, nothing until the first keyframe
+    pub fn mux(&mut self, frame: &DataFrame) -> Option<Vec<u8>> {
+        if frame.metadata["media"] != "video" {
+            return None;
+        }
+        let keyframe = match frame.metadata["type"].as_str() {
+            Some("keyframe") => true,
+            None => false,
+            _ => return None,
+        };
+        let nals = nal_units(&frame.data);
+        let mut out = vec![];
+        if keyframe {
+            let sps = nals.iter().find(|nal| nal[0] & 0x1f == 7);
+            let pps = nals.iter().find(|nal| nal[0] & 0x1f == 8);
+            if let (Some(sps), Some(pps)) = (sps, pps) {
+                let params = (sps.to_vec(), pps.to_vec());
+                if self.params.as_ref() != Some(&params) {
+                    out = init_segment(sps, pps)?;
+                    self.params = Some(params);
+                }
+            }
+        }
+        // waiting for the first keyframe
+        self.params.as_ref()?;
+
+        // 90kHz decode time from the frame timestamp, or the arrival time when it is not sent
+        let ts = match frame.metadata["ts"].as_f64() {
+            Some(ts) => (ts / 1000.0) as u64,
+            None => (self.clock.get_or_insert_with(Instant::now).elapsed().as_secs_f64() * TIMESCALE as f64) as u64,
+        };
+        let first_ts = *self.first_ts.get_or_insert(ts);
+        let decode_time = match self.last {
+            // timestamps going backward, continue from the previous frame
+            Some((last, duration)) if ts.saturating_sub(first_ts) <= last => last + duration as u64,
+            _ => ts.saturating_sub(first_ts),
+        };
+        let duration = self.last.map_or(DEFAULT_DURATION, |(last, duration)| (decode_time - last).try_into().unwrap_or(duration).max(1));
+        self.last = Some((decode_time, duration));
+
+        let sample = sample(&nals);
+        self.sequence += 1;
+        let flags = if keyframe { SAMPLE_SYNC } else { SAMPLE_NON_SYNC };
+        // moof size is known: 8 + mfhd 16 + traf (8 + tfhd 16 + tfdt 20 + trun 32), then the mdat header
+        let data_offset = 8 + 16 + 8 + 16 + 20 + 32 + 8;
+        let traf = mp4box(b"traf", &[
+            full_box(b"tfhd", 0, 0x02_0000, &u32s(&[TRACK_ID])),
+            full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes()),
+            full_box(b"trun", 0, 0x0701, &u32s(&[1, data_offset, duration, sample.len() as u32, flags])),
+        ].concat());
+        out.extend(mp4box(b"moof", &[full_box(b"mfhd", 0, 0, &u32s(&[self.sequence])), traf].concat()));
+        out.extend(mp4box(b"mdat", &sample));
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const CFG: [u8; 20] = [0, 0, 0, 1, 0x67, 0x42, 0xc0, 0x1e, 0xda, 0x05, 0x07, 0xe4, 0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80];
+
+    fn frame(keyframe: bool, ticks: u64) -> DataFrame {
+        let mut metadata = json!({ "media": "video", "ts": (ticks * 1000) as f64 });
+        let mut data = vec![];
+        if keyframe {
+            metadata["type"] = "keyframe".into();
+            data.extend_from_slice(&CFG);
+        }
+        data.extend_from_slice(&[0, 0, 0, 1, if keyframe { 0x65 } else { 0x41 }, 0x88, 0x84]);
+        DataFrame { metadata, data: data.into() }
+    }
+
+    // kind and content of the boxes
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut out = vec![];
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            out.push((data[4..8].try_into().unwrap(), &data[8..size]));
+            data = &data[size..];
+        }
+        out
+    }
+
+    fn kinds(data: &[u8]) -> Vec<[u8; 4]> {
+        boxes(data).into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    // decode time of the tfdt and data offset, duration and size of the trun
+    fn fragment(data: &[u8]) -> (u64, u32, u32, u32) {
+        let (_, moof) = boxes(data).into_iter().find(|(kind, _)| kind == b"moof").unwrap();
+        let (_, traf) = boxes(moof).into_iter().find(|(kind, _)| kind == b"traf").unwrap();
+        let traf = boxes(traf);
+        assert_eq!(traf.iter().map(|(kind, _)| kind).collect::<Vec<_>>(), [b"tfhd", b"tfdt", b"trun"]);
+        let decode_time = u64::from_be_bytes(traf[1].1[4..12].try_into().unwrap());
+        let trun: Vec<u32> = traf[2].1[4..].chunks(4).map(|b| u32::from_be_bytes(b.try_into().unwrap())).collect();
+        assert_eq!(trun[0], 1);
+        (decode_time, trun[1], trun[2], trun[3])
+    }
+
+    #[test]
+    fn init_segment_on_the_first_keyframe_and_when_the_parameter_sets_change() {
+        let mut muxer = Muxer::default();
+        assert!(muxer.mux(&frame(false, 0)).is_none());
+        assert_eq!(kinds(&muxer.mux(&frame(true, 0)).unwrap()), [*b"ftyp", *b"moov", *b"moof", *b"mdat"]);
+        assert_eq!(kinds(&muxer.mux(&frame(false, 3600)).unwrap()), [*b"moof", *b"mdat"]);
+        assert_eq!(kinds(&muxer.mux(&frame(true, 7200)).unwrap()), [*b"moof", *b"mdat"]);
+        let mut changed = frame(true, 10800);
+        let mut data = changed.data.to_vec();
+        data[18] = 0x3d;
+        changed.data = data.into();
+        assert_eq!(kinds(&muxer.mux(&changed).unwrap()), [*b"ftyp", *b"moov", *b"moof", *b"mdat"]);
+    }
+
+    #[test]
+    fn init_segment_after_a_reset() {
+        let mut muxer = Muxer::default();
+        let init = |reset: bool| DataFrame { metadata: json!({ "media": "video", "type": "init", "reset": reset }), data: CFG.to_vec().into() };
+        // nothing to reset before the first init segment
+        assert!(!muxer.reset(&init(true)));
+        muxer.mux(&frame(true, 0)).unwrap();
+        assert!(!muxer.reset(&init(false)));
+        assert!(muxer.reset(&init(true)));
+        // the same parameter sets are sent again from the next keyframe
+        assert!(muxer.mux(&frame(false, 3600)).is_none());
+        assert_eq!(kinds(&muxer.mux(&frame(true, 7200)).unwrap()), [*b"ftyp", *b"moov", *b"moof", *b"mdat"]);
+    }
+
+    #[test]
+    fn fragment_sizes_and_data_offset() {
+        let mut muxer = Muxer::default();
+        let out = muxer.mux(&frame(true, 0)).unwrap();
+        let fragment_start = boxes(&out)[..2].iter().map(|(_, content)| 8 + content.len()).sum::<usize>();
+        let out = &out[fragment_start..];
+        let (kind, moof) = boxes(out)[0];
+        assert_eq!((&kind, 8 + moof.len()), (b"moof", 100));
+        // the mdat only has the length prefixed slice, the parameter sets are in the init segment
+        let (kind, mdat) = boxes(out)[1];
+        assert_eq!((&kind, mdat), (b"mdat", &[0, 0, 0, 3, 0x65, 0x88, 0x84][..]));
+        let (_, data_offset, duration, size) = fragment(out);
+        // from the start of the moof to the sample
+        assert_eq!(data_offset, 108);
+        assert_eq!(out[data_offset as usize..], *mdat);
+        assert_eq!((duration, size), (DEFAULT_DURATION, 7));
+    }
+
+    #[test]
+    fn decode_time_from_the_first_frame() {
+        let mut muxer = Muxer::default();
+        let mut fragments = vec![];
+        for ticks in [900_000, 903_600, 907_200, 905_000, 912_000] {
+            let (decode_time, _, duration, _) = fragment(&muxer.mux(&frame(ticks == 900_000, ticks)).unwrap());
+            fragments.push((decode_time, duration));
+        }
+        assert_eq!(fragments[..3], [(0, DEFAULT_DURATION), (3600, 3600), (7200, 3600)]);
+        // timestamp going backward, continues from the previous frame with its duration
+        assert_eq!(fragments[3], (10800, 3600));
+        assert_eq!(fragments[4], (12000, 1200));
+    }
+}
@@ -0,0 +1,132 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    // address with an optional /prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{}: {}", addr, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("invalid prefix length {}", prefix))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // compare IPv4 clients of a dual stack socket as IPv4
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix as u32;
+        shift >= bits || net >> shift == ip >> shift
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    // denied addresses win over allowed ones, everything is allowed without allowlist
+    pub fn allowed(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefix_contains_its_whole_family() {
+        // a shift of 32 or 128 bits would overflow
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("10.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn host_prefix() {
+        for host in ["192.168.1.10", "192.168.1.10/32"] {
+            assert!(cidr(host).contains(ip("192.168.1.10")));
+            assert!(!cidr(host).contains(ip("192.168.1.11")));
+        }
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1").contains(ip("2001:db8::2")));
+        assert!(cidr("192.168.0.0/16").contains(ip("192.168.255.1")));
+        assert!(!cidr("192.168.0.0/16").contains(ip("192.169.0.1")));
+    }
+
+    #[test]
+    fn invalid_cidrs_are_rejected() {
+        for value in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/8/8", "10.0.0/8", "host/8"] {
+            assert!(value.parse::<Cidr>().is_err(), "{}", value);
+        }
+        assert!("::/128".parse::<Cidr>().is_ok());
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_match_ipv4_cidrs() {
+        assert!(cidr("192.168.1.0/24").contains(ip("::ffff:192.168.1.20")));
+        assert!(!cidr("192.168.1.0/24").contains(ip("::ffff:192.168.2.20")));
+        let filter = IpFilter { allow: vec![], deny: vec![cidr("192.168.1.20")] };
+        assert!(!filter.allowed(ip("::ffff:192.168.1.20")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = IpFilter { allow: vec![cidr("10.0.0.0/8")], deny: vec![cidr("10.1.0.0/16")] };
+        assert!(filter.allowed(ip("10.2.0.1")));
+        assert!(!filter.allowed(ip("10.1.0.1")));
+        // outside of the allowlist
+        assert!(!filter.allowed(ip("192.168.0.1")));
+        // everything not denied without allowlist
+        let filter = IpFilter { allow: vec![], deny: vec![cidr("10.1.0.0/16")] };
+        assert!(filter.allowed(ip("192.168.0.1")));
+        assert!(!filter.allowed(ip("10.1.2.3")));
+        assert!(IpFilter::default().allowed(ip("::1")));
+    }
+}
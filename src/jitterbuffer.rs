@@ -0,0 +1,69 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// holds the video frames of a stream before broadcasting them, paced on their timestamps: a frame is
+// released --buffer-ms after the time its timestamp gives relative to the first one
+
+use std::time::{Duration, Instant};
+use log::{debug, error};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::streamdef::DataFrame;
+
+// clock rate of the H.264 RTP timestamps
+const CLOCK_RATE: i64 = 90000;
+
+pub struct JitterBuffer {
+    delay: Duration,
+    // arrival time and timestamp of the frame the release times are computed from
+    anchor: Option<(Instant, i64)>,
+    queue: mpsc::UnboundedSender<(Instant, DataFrame)>,
+}
+
+impl JitterBuffer {
+    // the frames are released until the buffer is dropped and its queue emptied
+    pub fn new(delay: Duration, tx: broadcast::Sender<DataFrame>) -> Self {
+        let (queue, rx) = mpsc::unbounded_channel();
+        tokio::spawn(release(rx, tx));
+        Self { delay, anchor: None, queue }
+    }
+
+    pub fn push(&mut self, ts: i64, frame: DataFrame) {
+        let now = Instant::now();
+        let release = self.anchor
+            .map(|(time, anchor_ts)| time + Duration::from_micros(((ts - anchor_ts).max(0) * 1_000_000 / CLOCK_RATE) as u64) + self.delay)
+            .filter(|release| *release >= now && *release <= now + self.delay * 2);
+        let release = match release {
+            Some(release) => release,
+            // first frame, frame later than the buffer or timestamp jump: start again from this frame
+            None => {
+                if self.anchor.is_some() {
+                    debug!("jitter buffer anchored again at timestamp {}", ts);
+                }
+                self.anchor = Some((now, ts));
+                now + self.delay
+            },
+        };
+        let _ = self.queue.send((release, frame));
+    }
+
+    // metadata messages, released right after the frames already queued
+    pub fn push_next(&self, frame: DataFrame) {
+        let _ = self.queue.send((Instant::now(), frame));
+    }
+}
+
+async fn release(mut rx: mpsc::UnboundedReceiver<(Instant, DataFrame)>, tx: broadcast::Sender<DataFrame>) {
+    while let Some((release, frame)) = rx.recv().await {
+        tokio::time::sleep_until(release.into()).await;
+        if let Err(e) = tx.send(frame) {
+            error!("Error broadcasting message: {}", e);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// env_logger whose filters, in the RUST_LOG syntax, can be replaced at runtime
+
+use std::sync::{OnceLock, RwLock};
+use log::{Log, Metadata, Record};
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+struct ReloadableLogger {
+    // the filters as given and the logger applying them
+    inner: RwLock<(String, env_logger::Logger)>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().1.log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().1.flush()
+    }
+}
+
+fn build(filters: &str) -> env_logger::Logger {
+    env_logger::Builder::new()
+        .parse_write_style(&std::env::var("RUST_LOG_STYLE").unwrap_or_default())
+        .parse_filters(filters)
+        .build()
+}
+
+// RUST_LOG, or the default filters when not set
+pub fn init(default: &str) {
+    let filters = std::env::var("RUST_LOG").unwrap_or_else(|_| default.to_string());
+    let logger = build(&filters);
+    let max_level = logger.filter();
+    let logger = LOGGER.get_or_init(|| ReloadableLogger { inner: RwLock::new((filters, logger)) });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+pub fn filters() -> Option<String> {
+    LOGGER.get().map(|logger| logger.inner.read().unwrap().0.clone())
+}
+
+// invalid directives are ignored, as in RUST_LOG
+pub fn set_filters(filters: &str) {
+    if let Some(logger) = LOGGER.get() {
+        let new_logger = build(filters);
+        log::set_max_level(new_logger.filter());
+        *logger.inner.write().unwrap() = (filters.to_string(), new_logger);
+    }
+}
@@ -9,32 +9,223 @@
 
 use anyhow::Error;
 use actix_files::Files;
-use actix_web::{get, web, App, HttpServer, HttpRequest, HttpResponse};
+use actix_web::dev::Service;
+use actix_web::{get, put, http::{header::{self, HeaderName, HeaderValue}, KeepAlive}, middleware, web, App, HttpServer, HttpRequest, HttpResponse};
 use clap::Parser;
+use futures::future::Either;
+use futures::StreamExt;
 
-use log::info;
+use log::{info, warn};
 
 use serde_json::json;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
 use actix_web_actors::ws;
+use tokio::sync::{broadcast, watch};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 
 mod websocketservice;
 mod appcontext;
 mod rtspclient;
 mod streamdef;
+mod openapi;
+mod rtmp;
+mod socketopts;
+mod metrics;
+mod mpegts;
+mod fmp4;
+mod filesource;
+mod ipfilter;
+mod memory;
+mod statslog;
+mod sink;
+mod sei;
+mod logger;
+mod jitterbuffer;
+mod paramsets;
+#[cfg(feature = "systemd")]
+mod systemd;
+#[cfg(any(test, feature = "mock"))]
+mod mocksource;
+#[cfg(feature = "webrtc")]
+mod webrtcservice;
+#[cfg(feature = "embed-www")]
+mod embedded;
+#[cfg(test)]
+mod fakecamera;
 
-use streamdef::StreamsDef;
+use streamdef::{DataFrame, StreamsDef};
 
 #[derive(Parser)]
 pub struct Opts {
     #[clap(short)]
-    config: String,
+    config: Option<String>,
 
-    #[clap(short)]
-    transport: Option<String>,
+    /// RTSP url of a single stream served as /stream, "-" reads it from stdin
+    #[clap(long, env = "RTSP_URL", hide_env_values = true, conflicts_with = "url_file")]
+    url: Option<String>,
+
+    /// file containing the RTSP url of a single stream served as /stream
+    #[clap(long)]
+    url_file: Option<String>,
+
+    #[clap(flatten)]
+    rtsp: rtspclient::RtspOpts,
+
+    /// number of http worker threads (default: one per core)
+    #[clap(long)]
+    workers: Option<usize>,
+
+    /// run the RTSP clients on a dedicated runtime with this number of threads (default: shared with the main runtime)
+    #[clap(long)]
+    rtsp_threads: Option<usize>,
+
+    /// image sent to websocket clients before live video, overridden by "poster" in the config
+    #[clap(long)]
+    poster: Option<String>,
+
+    /// seconds for a client to send the request headers before it is disconnected, against slowloris (0: no timeout)
+    #[clap(long, default_value_t = 5)]
+    client_request_timeout: u64,
+
+    /// seconds an idle http connection is kept open between requests (0: closed after each response)
+    #[clap(long, default_value_t = 5)]
+    keep_alive: u64,
+
+    /// connections accepted by each http worker, including the websockets, further ones wait in the listen backlog
+    #[clap(long, default_value_t = 1024)]
+    max_connections: usize,
+
+    /// seconds to keep serving connected websocket clients on shutdown
+    #[clap(long)]
+    shutdown_grace: Option<u64>,
+
+    /// print the content of the configured streams as json and exit
+    #[clap(long)]
+    probe: bool,
+
+    /// validate the configuration, print the problems found and exit
+    #[clap(long, alias = "dry-run")]
+    check_config: bool,
+
+    /// serve every H.264 video stream of the RTSP sessions, such as the channels of a NVR, on /<name>/<index>, counted by a DESCRIBE at startup
+    #[clap(long)]
+    all_video_streams: bool,
+
+    /// only serve the api, without connecting to the cameras nor serving the websockets
+    #[clap(long)]
+    control_only: bool,
+
+    /// serve all the streams multiplexed on /ws/all
+    #[clap(long)]
+    ws_all: bool,
+
+    /// disable Nagle's algorithm on http connections
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    tcp_nodelay: bool,
+
+    /// socket send buffer size in bytes (default: system default)
+    #[clap(long)]
+    send_buffer: Option<usize>,
+
+    /// memory budget in MB, all the streams broadcast only keyframes and the lagging websocket clients are disconnected when the process gets close to it
+    #[clap(long)]
+    max_memory_mb: Option<u64>,
+
+    /// file where a row is appended per broadcasted frame, CSV or JSON lines when ending with .json or .jsonl
+    #[clap(long)]
+    stats_log: Option<String>,
+
+    /// size in MB of --stats-log before it is rotated
+    #[clap(long, default_value_t = 100)]
+    stats_log_max_mb: u64,
+
+    /// refuse websocket and sse clients of paused streams instead of keeping them connected until resumed
+    #[clap(long)]
+    reject_paused: bool,
+
+    /// header added to all the http responses, as "Name: value", can be repeated
+    #[clap(long = "header", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// header added to the static file responses only, can be repeated
+    #[clap(long = "static-header", value_parser = parse_header)]
+    static_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// only accept http clients from this address or CIDR, can be repeated
+    #[clap(long = "allow-ip")]
+    allow_ip: Vec<ipfilter::Cidr>,
+
+    /// refuse http clients from this address or CIDR, can be repeated
+    #[clap(long = "deny-ip")]
+    deny_ip: Vec<ipfilter::Cidr>,
+
+    /// bearer token required by the api changing the server configuration, such as /api/loglevel or the pause and resume of the streams (default: refused), and by /api/log?level= once set (default: open)
+    #[clap(long, env = "RTSP2WEB_API_TOKEN", hide_env_values = true)]
+    api_token: Option<String>,
+
+    /// token required in the ?token= query parameter of the websocket, keyframe, sse and WebRTC endpoints, overridden by "token" in the config (default: off)
+    #[clap(long, env = "RTSP2WEB_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// favor latency over completeness: no --buffer-ms and a smaller broadcast so lagging clients skip closer to the live, overridden by "low_latency" in the config
+    #[clap(long)]
+    low_latency: bool,
+
+    /// log every http request
+    #[clap(long)]
+    access_log: bool,
+
+    /// DSCP value marking the http, websocket and RTMP egress traffic
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..64))]
+    dscp: Option<u8>,
+
+    /// http port
+    #[clap(long, default_value_t = 8080)]
+    port: u16,
+
+    /// path prefix the server is reached at, e.g. /cameras behind a reverse proxy forwarding the full path
+    #[clap(long, default_value = "")]
+    base_path: String,
+
+    /// prefix of the websocket paths, streams are served on <prefix>/<name>
+    #[clap(long, default_value = "")]
+    ws_path: String,
+
+    /// path of the streams api
+    #[clap(long, default_value = "/api/streams")]
+    streams_path: String,
+
+    /// path of the version api
+    #[clap(long, default_value = "/api/version")]
+    version_path: String,
+
+    /// maximum size in bytes of the websocket messages received from clients, the frames sent are not limited
+    #[clap(long, default_value_t = 65536)]
+    ws_max_frame_size: usize,
+
+    /// default format of the frames sent on the stream websockets, clients choose theirs with ?format= or the subprotocol
+    #[clap(long, value_enum, default_value = "json")]
+    output_format: websocketservice::OutputFormat,
+
+    /// access log format, see actix_web::middleware::Logger
+    #[clap(long, default_value = "%a \"%r\" %s %b %Dms")]
+    access_log_format: String,
+}
+
+fn parse_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = value.split_once(':').ok_or("expected \"Name: value\"")?;
+    let name = HeaderName::try_from(name.trim()).map_err(|e| e.to_string())?;
+    let value = HeaderValue::try_from(value.trim()).map_err(|e| e.to_string())?;
+    Ok((name, value))
+}
+
+fn default_headers(headers: &[(HeaderName, HeaderValue)]) -> middleware::DefaultHeaders {
+    headers.iter().fold(middleware::DefaultHeaders::new(), |middleware, header| middleware.add(header.clone()))
 }
 
 fn read_json_file(file_path: &str) -> Result<serde_json::Value, Error> {
@@ -45,95 +236,964 @@ fn read_json_file(file_path: &str) -> Result<serde_json::Value, Error> {
     Ok(data)
 }
 
+fn read_poster(file_path: &str) -> Result<DataFrame, Error> {
+    let data = std::fs::read(file_path)?;
+    let mime = match file_path.rsplit('.').next().map(|ext| ext.to_lowercase()).as_deref() {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        _ => "image/jpeg",
+    };
+    let metadata = json!({
+        "media": "image",
+        "type": "poster",
+        "mime": mime,
+    });
+    Ok(DataFrame { metadata, data: data.into() })
+}
+
+
+// url given outside of the config, kept out of the process arguments when it contains credentials
+fn read_url(opts: &Opts) -> Result<Option<String>, Error> {
+    let url = match (&opts.url, &opts.url_file) {
+        (Some(url), _) if url == "-" => {
+            let mut url = String::new();
+            std::io::stdin().read_line(&mut url)?;
+            url
+        },
+        (Some(url), _) => url.clone(),
+        (None, Some(file_path)) => std::fs::read_to_string(file_path)?,
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(url.trim().to_string()))
+}
+
+fn stream_from_config(value: &serde_json::Value, opts: &Opts) -> StreamsDef {
+    let url = url::Url::parse(value["video"].as_str().unwrap()).unwrap().clone();
+    let mut streamdef = StreamsDef::new(url);
+    if let Some(username) = value["username"].as_str() {
+        streamdef.creds = Some(retina::client::Credentials {
+            username: username.to_string(),
+            password: value["password"].as_str().unwrap_or_default().to_string(),
+        });
+    }
+    streamdef.transport = value["transport"].as_str().map(str::to_string);
+    streamdef.user_agent = value["user_agent"].as_str().map(str::to_string).or(opts.rtsp.rtsp_user_agent.clone());
+    streamdef.token = value["token"].as_str().map(str::to_string).or(opts.token.clone());
+    streamdef.stream_index = value["stream_index"].as_u64().map(|i| i as usize);
+    if let Some(egress) = value["egress"].as_str() {
+        match url::Url::parse(egress) {
+            Ok(egress) if egress.scheme() == "rtmp" => streamdef.egress = Some(egress),
+            Ok(egress) => warn!("Unsupported egress {}, only rtmp is available", egress),
+            Err(err) => warn!("Error parsing egress {}: {:?}", egress, err),
+        }
+    }
+    streamdef.record = value["record"].as_str().map(str::to_string);
+    streamdef.buffer = Duration::from_millis(opts.rtsp.buffer_ms);
+    if value["low_latency"].as_bool().unwrap_or(opts.low_latency) {
+        streamdef.set_low_latency();
+    }
+    if let Some(poster) = value["poster"].as_str().or(opts.poster.as_deref()) {
+        match read_poster(poster) {
+            Ok(poster) => streamdef.poster = Some(poster),
+            Err(err) => warn!("Error reading poster {}: {:?}", poster, err),
+        }
+    }
+    streamdef
+}
+
+// with --all-video-streams, the video streams of a RTSP session, one when it can't be described at startup
+async fn video_stream_count(key: &str, streamdef: &StreamsDef, opts: &Opts) -> usize {
+    if !opts.all_video_streams || opts.control_only || !streamdef.url.scheme().starts_with("rtsp") {
+        return 1;
+    }
+    match rtspclient::video_streams(streamdef, opts.rtsp.describe_timeout).await {
+        Ok(count) => {
+            info!("{}: {} video streams", key, count);
+            count
+        },
+        Err(err) => {
+            warn!("{}: video streams not counted, serving only the first: {}", key, err);
+            1
+        },
+    }
+}
+
+fn routes(opts: &Opts) -> appcontext::Routes {
+    appcontext::Routes {
+        base: opts.base_path.trim_end_matches('/').to_string(),
+        ws: opts.ws_path.trim_end_matches('/').to_string(),
+        streams: opts.streams_path.trim_end_matches('/').to_string(),
+        version: opts.version_path.clone(),
+    }
+}
+
+// problems in the configuration, found without connecting to the cameras nor listening
+// the config of a stream asking for custom RTSP headers is refused rather than silently ignored
+const HEADERS_UNSUPPORTED: &str = "\"headers\" is not supported, retina sends the RTSP requests and only their User-Agent can be set with \"user_agent\"";
+
+fn check_config(opts: &Opts) -> Vec<String> {
+    let mut problems = vec![];
+    let mut urls = serde_json::Map::new();
+    if let Some(config) = &opts.config {
+        match read_json_file(config) {
+            Ok(data) => match data["urls"].as_object() {
+                Some(entries) => urls = entries.clone(),
+                None => problems.push(format!("{}: missing \"urls\" object", config)),
+            },
+            Err(err) => problems.push(format!("{}: {}", config, err)),
+        }
+    }
+    match read_url(opts) {
+        Ok(Some(url)) => {
+            if urls.contains_key("stream") {
+                problems.push("--url replaces the \"stream\" entry of the config".to_string());
+            }
+            urls.insert("stream".to_string(), json!({ "video": url }));
+        },
+        Ok(None) if opts.config.is_none() => problems.push("one of -c, --url, --url-file or RTSP_URL is required".to_string()),
+        Ok(None) => {},
+        Err(err) => problems.push(format!("url: {}", err)),
+    }
 
+    for (option, path) in [("--base-path", &opts.base_path), ("--ws-path", &opts.ws_path), ("--streams-path", &opts.streams_path), ("--version-path", &opts.version_path)] {
+        if !path.is_empty() && !path.starts_with('/') {
+            problems.push(format!("{} {} should start with /", option, path));
+        }
+    }
+    let check_transport = |name: &str, transport: &str, problems: &mut Vec<String>| {
+        if let Err(err) = transport.parse::<retina::client::Transport>() {
+            problems.push(format!("{}: invalid transport {}: {}", name, transport, err));
+        }
+    };
+    let check_poster = |name: &str, poster: &str, problems: &mut Vec<String>| {
+        if !std::path::Path::new(poster).is_file() {
+            problems.push(format!("{}: poster {} not found", name, poster));
+        }
+    };
+    if let Some(transport) = &opts.rtsp.transport {
+        check_transport("--transport", transport, &mut problems);
+    }
+    if let Some(poster) = &opts.poster {
+        check_poster("--poster", poster, &mut problems);
+    }
+
+    // websocket paths are routed with the api, the first route matching wins
+    let routes = routes(opts);
+    let mut paths: HashMap<String, String> = HashMap::new();
+    for path in [routes.version.clone(), routes.streams.clone(), "/api/selftest".to_string(), "/api/openapi.json".to_string(), "/metrics".to_string(), "/api/log".to_string()] {
+        paths.insert(path, "the api".to_string());
+    }
+    if opts.ws_all {
+        paths.insert("/ws/all".to_string(), "--ws-all".to_string());
+    }
+    for (key, value) in urls.iter() {
+        match value["video"].as_str().map(url::Url::parse) {
+            None => problems.push(format!("{}: missing \"video\" url", key)),
+            // the url may contain credentials, only its error is reported
+            Some(Err(err)) => problems.push(format!("{}: invalid video url: {}", key, err)),
+            Some(Ok(url)) => match url.scheme() {
+                "rtsp" => {},
+                "file" | "mock" if opts.rtsp.media == rtspclient::MediaSelection::Audio => problems.push(format!("{}: {}: urls have no audio for --media audio", key, url.scheme())),
+                "file" => match url.to_file_path() {
+                    Ok(path) if path.exists() => {},
+                    Ok(path) => problems.push(format!("{}: {} not found", key, path.display())),
+                    Err(_) => problems.push(format!("{}: invalid file url", key)),
+                },
+                "mock" if cfg!(feature = "mock") => {},
+                scheme => problems.push(format!("{}: unsupported scheme {}", key, scheme)),
+            },
+        }
+        if value.get("headers").is_some() {
+            problems.push(format!("{}: {}", key, HEADERS_UNSUPPORTED));
+        }
+        if let Some(transport) = value["transport"].as_str() {
+            check_transport(key, transport, &mut problems);
+        }
+        if let Some(poster) = value["poster"].as_str() {
+            check_poster(key, poster, &mut problems);
+        }
+        if let Some(egress) = value["egress"].as_str() {
+            match url::Url::parse(egress) {
+                Ok(egress) if egress.scheme() == "rtmp" => {},
+                Ok(egress) => problems.push(format!("{}: unsupported egress {}, only rtmp is available", key, egress.scheme())),
+                Err(err) => problems.push(format!("{}: invalid egress url: {}", key, err)),
+            }
+        }
+        if let Some(record) = value["record"].as_str() {
+            let dir = std::path::Path::new(record).parent().filter(|dir| !dir.as_os_str().is_empty());
+            if dir.is_some_and(|dir| !dir.is_dir()) {
+                problems.push(format!("{}: record directory of {} not found", key, record));
+            }
+        }
+        let wsurl = "/".to_string() + key;
+        let wsurls = if value["substream"].as_bool().unwrap_or(false) {
+            vec![format!("{}/main", wsurl), format!("{}/sub", wsurl)]
+        } else {
+            vec![wsurl]
+        };
+        for wsurl in wsurls {
+            let path = routes.websocket(&wsurl);
+            if let Some(other) = paths.insert(path.clone(), key.clone()) {
+                problems.push(format!("{}: path {} is already used by {}", key, path, other));
+            }
+        }
+    }
+    // with --all-video-streams the rtsp sessions may also be served on /<name>/<index>, counted at startup
+    if opts.all_video_streams {
+        for (key, value) in urls.iter() {
+            let rtsp = value["video"].as_str().is_some_and(|url| url.starts_with("rtsp"));
+            if !rtsp || value["substream"].as_bool().unwrap_or(false) {
+                continue;
+            }
+            let prefix = routes.websocket(&format!("/{}/", key));
+            for (path, other) in paths.iter().filter(|(path, _)| path.strip_prefix(&prefix).is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))) {
+                problems.push(format!("{}: path {} may be used by the video streams of {} with --all-video-streams", other, path, key));
+            }
+        }
+    }
+    problems
+}
 
 #[tokio::main]
 async fn main() {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    logger::init("info");
 
     let opts = Opts::parse();
 
+    if opts.check_config {
+        let problems = check_config(&opts);
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        if problems.is_empty() {
+            println!("configuration ok");
+        }
+        std::process::exit(if problems.is_empty() { 0 } else { 1 });
+    }
+
     let mut streams_defs = HashMap::new();
-    match read_json_file(opts.config.as_str()) {
-        Ok(data) => {
-            let urls = data["urls"].as_object().unwrap();
-            for (key, value) in urls.into_iter() {
-                let url = url::Url::parse(value["video"].as_str().unwrap()).unwrap().clone();
-                let wsurl = "/".to_string() + key;
-                streams_defs.insert(wsurl, Arc::new(Mutex::new(StreamsDef::new(url))));
-            }
+    let mut sessions = vec![];
+    let mut urls = serde_json::Map::new();
+    if let Some(config) = &opts.config {
+        match read_json_file(config) {
+            Ok(data) => urls = data["urls"].as_object().unwrap().clone(),
+            Err(err) => println!("Error reading JSON file: {:?}", err),
+        }
+    }
+    match read_url(&opts) {
+        Ok(Some(url)) => {
+            urls.insert("stream".to_string(), json!({ "video": url }));
+        },
+        Ok(None) if opts.config.is_none() => {
+            eprintln!("one of -c, --url, --url-file or RTSP_URL is required");
+            std::process::exit(2);
         },
-        Err(err) => println!("Error reading JSON file: {:?}", err),
+        Ok(None) => {},
+        Err(err) => {
+            eprintln!("Error reading url: {:?}", err);
+            std::process::exit(2);
+        },
+    }
+    for (key, value) in urls.iter() {
+        if value.get("headers").is_some() {
+            eprintln!("{}: {}", key, HEADERS_UNSUPPORTED);
+            std::process::exit(2);
+        }
+        let wsurl = "/".to_string() + key;
+        let session: Vec<_> = if value["substream"].as_bool().unwrap_or(false) {
+            ["main", "sub"].iter().map(|profile| {
+                let mut streamdef = stream_from_config(value, &opts);
+                streamdef.profile = Some(profile.to_string());
+                // only the main stream is recorded
+                if *profile == "sub" {
+                    streamdef.record = None;
+                }
+                (format!("{}/{}", wsurl, profile), Arc::new(Mutex::new(streamdef)))
+            }).collect()
+        } else {
+            let streamdef = stream_from_config(value, &opts);
+            match video_stream_count(key, &streamdef, &opts).await {
+                count if count > 1 => (0..count).map(|index| {
+                    let mut streamdef = stream_from_config(value, &opts);
+                    streamdef.profile = Some(index.to_string());
+                    // only the first stream is recorded
+                    if index > 0 {
+                        streamdef.record = None;
+                    }
+                    (format!("{}/{}", wsurl, index), Arc::new(Mutex::new(streamdef)))
+                }).collect(),
+                _ => vec![(wsurl, Arc::new(Mutex::new(streamdef)))],
+            }
+        };
+        // pausing one of the streams pauses the whole RTSP session
+        let pause = session[0].1.lock().unwrap().pause.clone();
+        session.iter().for_each(|(_, streamdef)| streamdef.lock().unwrap().pause = pause.clone());
+        // the paths of --all-video-streams are only known once the sessions are described
+        if let Some((path, _)) = session.iter().find(|(path, _)| streams_defs.contains_key(path)) {
+            eprintln!("{}: path {} is already used by another stream", key, path);
+            std::process::exit(2);
+        }
+        sessions.push(session.iter().map(|(_, streamdef)| streamdef.clone()).collect::<Vec<_>>());
+        streams_defs.extend(session);
+    }
+
+    if opts.probe {
+        let mut report = json!({});
+        let mut success = true;
+        for (key, streamdef) in &streams_defs {
+            let stream = streamdef.lock().unwrap().clone();
+            report[key] = match rtspclient::probe(&stream).await {
+                Ok(info) => info,
+                Err(err) => {
+                    success = false;
+                    json!({ "error": err.to_string() })
+                },
+            };
+        }
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        std::process::exit(if success { 0 } else { 1 });
     }
 
     // start the RTSP clients
-    let app_context = appcontext::AppContext::new(streams_defs);
-    app_context.streams.values().for_each(|streamdef| {
-        let stream = streamdef.lock().unwrap();
-        tokio::spawn(rtspclient::run(stream.url.clone(), opts.transport.clone(), stream.tx.clone()));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    if let Some(path) = &opts.stats_log {
+        match statslog::StatsLog::open(path, opts.stats_log_max_mb) {
+            Ok(stats) => {
+                let stats = Arc::new(stats);
+                streams_defs.values().for_each(|streamdef| streamdef.lock().unwrap().stats = Some(stats.clone()));
+            },
+            Err(err) => {
+                eprintln!("Error opening {}: {:?}", path, err);
+                std::process::exit(2);
+            },
+        }
+    }
+    let memory = opts.max_memory_mb.map(|mb| Arc::new(memory::MemoryBudget::new(mb)));
+    if let Some(memory) = &memory {
+        streams_defs.values().for_each(|streamdef| streamdef.lock().unwrap().memory = Some(memory.clone()));
+        tokio::spawn(memory::run(memory.clone(), shutdown_rx.clone()));
+    }
+    let mut app_context = appcontext::AppContext::new(streams_defs);
+    app_context.ws_all = opts.ws_all;
+    app_context.control_only = opts.control_only;
+    app_context.output_format = opts.output_format;
+    app_context.reject_paused = opts.reject_paused;
+    app_context.api_token = opts.api_token.clone();
+    app_context.ws_max_frame_size = opts.ws_max_frame_size;
+    app_context.memory = memory;
+    app_context.routes = routes(&opts);
+    // http workers have their own threads, the RTSP clients share the main runtime unless isolated
+    let rtsp_runtime = opts.rtsp_threads.map(|threads| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads.max(1))
+            .thread_name("rtsp")
+            .enable_all()
+            .build()
+            .unwrap()
     });
+    let rtsp_handle = rtsp_runtime.as_ref().map_or_else(tokio::runtime::Handle::current, |runtime| runtime.handle().clone());
+    if opts.control_only {
+        info!("control only, the cameras are not connected");
+        sessions.clear();
+    }
+    let rtsp_tasks: Vec<_> = sessions.into_iter().map(|session| {
+        rtsp_handle.spawn(rtspclient::run(session, opts.rtsp.clone(), shutdown_rx.clone()))
+    }).collect();
+    let streams_defs = app_context.streams.clone();
+    if let Some(interval) = opts.rtsp.heartbeat_interval.filter(|_| !opts.control_only) {
+        for streamdef in streams_defs.values() {
+            tokio::spawn(rtspclient::heartbeat(streamdef.clone(), interval, shutdown_rx.clone()));
+        }
+    }
+
+    #[cfg(feature = "systemd")]
+    tokio::spawn(systemd::run(streams_defs.clone()));
+
+    // start the RTMP egress
+    let egress_tasks: Vec<_> = streams_defs.values().filter(|_| !opts.control_only).filter_map(|streamdef| {
+        let stream = streamdef.lock().unwrap();
+        let egress = stream.egress.clone()?;
+        Some(tokio::spawn(rtmp::run(egress, stream.rx.resubscribe(), opts.dscp, shutdown_rx.clone())))
+    }).collect();
+
+    // the sinks consuming the frames next to the websocket clients, custom integrations are added to this list
+    let mut sinks: Vec<(broadcast::Receiver<DataFrame>, Box<dyn sink::FrameSink>)> = vec![];
+    for streamdef in streams_defs.values().filter(|_| !opts.control_only) {
+        let stream = streamdef.lock().unwrap();
+        if let Some(record) = &stream.record {
+            match sink::RecordSink::create(record) {
+                Ok(recorder) => sinks.push((stream.rx.resubscribe(), Box::new(recorder))),
+                Err(err) => warn!("Error creating {}: {:?}", record, err),
+            }
+        }
+    }
+    let sink_tasks: Vec<_> = sinks.into_iter().map(|(rx, sink)| tokio::spawn(sink::run(sink, rx, shutdown_rx.clone()))).collect();
 
     // Start the Actix web server
     info!("start actix web server");
-    HttpServer::new( move || {
-        let mut app = App::new().app_data(web::Data::new(app_context.clone()));
-
-        for key in app_context.streams.keys() {
-            app = app.route(key, web::get().to(ws_index));
+    let tcp_nodelay = opts.tcp_nodelay;
+    let send_buffer = opts.send_buffer;
+    let dscp = opts.dscp;
+    if let Some(dscp) = dscp {
+        info!("marking http and egress traffic with DSCP {}, RTP sockets are not exposed by retina and are left unmarked", dscp);
+    }
+    let access_log = opts.access_log;
+    let access_log_format = opts.access_log_format.clone();
+    let ip_filter = ipfilter::IpFilter { allow: opts.allow_ip.clone(), deny: opts.deny_ip.clone() };
+    let headers = opts.headers.clone();
+    let static_headers = opts.static_headers.clone();
+    let has_index = std::path::Path::new("./www/index.html").is_file();
+    #[cfg(feature = "embed-www")]
+    let embedded = !has_index && embedded::has_index();
+    #[cfg(not(feature = "embed-www"))]
+    let embedded = false;
+    if embedded {
+        info!("./www/index.html not found, serving the embedded player");
+    } else if !has_index {
+        warn!("./www/index.html not found, / serves a page linking the api");
+    }
+    let mut server = HttpServer::new( move || {
+        let routes = &app_context.routes;
+        let mut scope = web::scope(&routes.base);
+        if !app_context.control_only {
+            for key in app_context.streams.keys() {
+                scope = scope.route(&routes.websocket(key), web::get().to(ws_index));
+            }
+            if app_context.ws_all {
+                scope = scope.route("/ws/all", web::get().to(ws_all));
+            }
         }
 
-        app.service(version)
-            .service(streams)
+        scope = scope.route(&routes.version, web::get().to(version))
+            .route(&routes.streams, web::get().to(streams))
+            .route(&format!("{}/{{name:.*}}/keyframe", routes.streams), web::get().to(stream_keyframe))
+            .route(&format!("{}/{{name:.*}}/events", routes.streams), web::get().to(stream_events))
+            .route(&format!("{}/{{name:.*}}/parameters", routes.streams), web::get().to(stream_parameters))
+            .route(&format!("{}/{{name:.*}}/pause", routes.streams), web::post().to(stream_pause))
+            .route(&format!("{}/{{name:.*}}/resume", routes.streams), web::post().to(stream_resume));
+        if !app_context.control_only {
+            scope = scope.route(&format!("{}/{{name:.*}}/sse", routes.streams), web::get().to(stream_sse));
+        }
+        #[cfg(feature = "webrtc")]
+        if !app_context.control_only {
+            scope = scope.route(&format!("{}/{{name:.*}}/webrtc", routes.streams), web::post().to(stream_webrtc));
+        }
+        scope = scope.route(&format!("{}/{{name:.*}}", routes.streams), web::get().to(stream_info))
             .service(logger_level)
-            .service(web::redirect("/", "/index.html"))
-            .service(Files::new("/", "./www"))
-    })
-    .bind(("0.0.0.0", 8080)).unwrap()
-    .run()
-    .await
-    .unwrap();
+            .service(log_filters)
+            .service(set_log_filters)
+            .service(openapi_doc)
+            .service(selftest)
+            .service(metrics_text)
+            .service(metrics_json);
+        scope = if has_index || embedded {
+            scope.service(web::redirect("/", routes.public("/index.html")))
+        } else {
+            scope.route("/", web::get().to(index_fallback))
+        };
+        #[cfg(feature = "embed-www")]
+        if embedded {
+            scope = scope.service(web::scope("").wrap(default_headers(&static_headers)).route("/{path:.*}", web::get().to(embedded::serve)));
+        }
+        if !embedded {
+            scope = scope.service(web::scope("").wrap(default_headers(&static_headers)).service(Files::new("/", "./www")));
+        }
 
+        let ip_filter = ip_filter.clone();
+        App::new().app_data(web::Data::new(app_context.clone()))
+            .wrap_fn(move |req, srv| {
+                let peer = req.peer_addr().map(|addr| addr.ip());
+                match peer {
+                    Some(ip) if !ip_filter.is_empty() && !ip_filter.allowed(ip) => {
+                        warn!("denied {} {}", ip, req.path());
+                        Either::Left(futures::future::ok(req.into_response(HttpResponse::Forbidden().finish())))
+                    },
+                    _ => Either::Right(srv.call(req)),
+                }
+            })
+            .wrap(default_headers(&headers))
+            .wrap(middleware::Condition::new(access_log, middleware::Logger::new(&access_log_format)))
+            .service(scope)
+    });
+    server = server.on_connect(move |conn, _ext| {
+        if let Some(sock) = conn.downcast_ref::<actix_web::rt::net::TcpStream>() {
+            if let Err(err) = sock.set_nodelay(tcp_nodelay) {
+                warn!("Error setting TCP_NODELAY: {:?}", err);
+            }
+            if let Some(size) = send_buffer {
+                if let Err(err) = socket2::SockRef::from(sock).set_send_buffer_size(size) {
+                    warn!("Error setting send buffer size: {:?}", err);
+                }
+            }
+            if let Some(dscp) = dscp {
+                if let Err(err) = socketopts::set_dscp(socket2::SockRef::from(sock), dscp) {
+                    warn!("Error setting DSCP {}: {:?}", dscp, err);
+                }
+            }
+        }
+    });
+    if let Some(workers) = opts.workers {
+        server = server.workers(workers);
+    }
+    if let Some(grace) = opts.shutdown_grace {
+        server = server.shutdown_timeout(grace);
+    }
+    server = server.client_request_timeout(Duration::from_secs(opts.client_request_timeout))
+        .keep_alive(match opts.keep_alive {
+            0 => KeepAlive::Disabled,
+            seconds => KeepAlive::Timeout(Duration::from_secs(seconds)),
+        })
+        .max_connections(opts.max_connections);
+    let server = server.disable_signals()
+        .bind(("0.0.0.0", opts.port)).unwrap()
+        .run();
+
+    // stop accepting connections on ctrl-c, keep serving clients during the grace period
+    let handle = server.handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let clients: u32 = streams_defs.values().map(|streamdef| streamdef.lock().unwrap().count).sum();
+            info!("shutting down, draining {} websocket clients", clients);
+            handle.stop(true).await;
+        }
+    });
+    server.await.unwrap();
+
+    // then teardown the RTSP sessions
+    let _ = shutdown_tx.send(true);
+    for task in rtsp_tasks {
+        let _ = task.await;
+    }
+    for task in egress_tasks.into_iter().chain(sink_tasks) {
+        let _ = task.await;
+    }
+    // a runtime cannot be dropped from an async context
+    if let Some(runtime) = rtsp_runtime {
+        runtime.shutdown_background();
+    }
 
     info!("Done");
 }
 
+// the format of a websocket client: ?format=, else the first supported subprotocol requested, else the configured one.
+// The subprotocols requested must include the one of the format.
+fn client_format(req: &HttpRequest, default: websocketservice::OutputFormat) -> Result<websocketservice::OutputFormat, String> {
+    let requested: Vec<_> = req.headers().get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    let query = url::form_urlencoded::parse(req.query_string().as_bytes()).find(|(key, _)| key == "format").map(|(_, value)| value);
+    let format = match query {
+        Some(value) => websocketservice::OutputFormat::from_query(&value).ok_or_else(|| format!("unsupported format {}", value))?,
+        None => requested.iter()
+            .find_map(|protocol| websocketservice::OutputFormat::ALL.into_iter().find(|format| format.protocol() == *protocol))
+            .unwrap_or(default),
+    };
+    if !requested.is_empty() && !requested.contains(&format.protocol()) {
+        return Err(format!("unsupported subprotocol, expected {}", format.protocol()));
+    }
+    Ok(format)
+}
+
+// upgrade answering the subprotocol of the format, when requested
+fn ws_start(service: websocketservice::WebsocketService, format: websocketservice::OutputFormat, req: &HttpRequest, stream: web::Payload) -> Result<HttpResponse, actix_web::Error> {
+    let max_frame_size = req.app_data::<web::Data<appcontext::AppContext>>().map_or(65536, |data| data.ws_max_frame_size);
+    let protocols = [format.protocol()];
+    ws::WsResponseBuilder::new(service, req, stream).protocols(&protocols).frame_size(max_frame_size).start()
+}
+
 // Websocket handler
 pub async fn ws_index(req: HttpRequest, stream: web::Payload, data: web::Data<appcontext::AppContext>) -> Result<HttpResponse, actix_web::Error> {
     let app_context = data.get_ref();
-    let wsurl = req.path().to_string();
+    let wsurl = req.path().strip_prefix(&app_context.routes.public(&app_context.routes.ws)).unwrap_or_default().to_string();
     if app_context.streams.contains_key(&wsurl) {
         let wscontext =  app_context.streams[&wsurl].to_owned();
-        let rx = wscontext.lock().unwrap().rx.resubscribe();
-        Ok(ws::start(websocketservice::WebsocketService{ wsurl, rx, wscontext }, &req, stream)?)
+        if !check_token(&wscontext.lock().unwrap(), &req) {
+            return Ok(HttpResponse::Unauthorized().finish());
+        }
+        if app_context.reject_paused && *wscontext.lock().unwrap().pause.borrow() {
+            return Ok(HttpResponse::ServiceUnavailable().body("stream is paused"));
+        }
+        let format = match client_format(&req, app_context.output_format) {
+            Ok(format) => format,
+            Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+        };
+        ws_start(websocketservice::WebsocketService::new(wsurl, wscontext, format), format, &req, stream)
     } else {
         Ok(HttpResponse::NotFound().finish())
     }
 }
 
-#[get("/api/streams")]
+// the token query parameter, compared in constant time, when the stream requires one
+fn check_token(stream: &StreamsDef, req: &HttpRequest) -> bool {
+    let expected = match &stream.token {
+        Some(token) => token.as_bytes(),
+        None => return true,
+    };
+    let token = url::form_urlencoded::parse(req.query_string().as_bytes()).find(|(key, _)| key == "token").map(|(_, value)| value);
+    token.is_some_and(|token| token_matches(token.as_bytes(), expected))
+}
+
+// constant time comparison, only the length of the expected token can be guessed
+fn token_matches(token: &[u8], expected: &[u8]) -> bool {
+    token.len() == expected.len() && token.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Authorization: Bearer with the --api-token, refused when it is not configured
+fn api_authorized(req: &HttpRequest, data: &appcontext::AppContext) -> bool {
+    match (&data.api_token, req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok())) {
+        (Some(expected), Some(authorization)) => authorization.strip_prefix("Bearer ").is_some_and(|token| token_matches(token.as_bytes(), expected.as_bytes())),
+        _ => false,
+    }
+}
+
+// Websocket handler forwarding all the streams
+pub async fn ws_all(req: HttpRequest, stream: web::Payload, data: web::Data<appcontext::AppContext>) -> Result<HttpResponse, actix_web::Error> {
+    // the token of each stream cannot be checked on a single connection
+    if data.get_ref().streams.values().any(|streamdef| streamdef.lock().unwrap().token.is_some()) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    match client_format(&req, websocketservice::OutputFormat::Json) {
+        Ok(websocketservice::OutputFormat::Json) => {},
+        Ok(_) => return Ok(HttpResponse::BadRequest().body("multiplexed streams are only sent in the json format")),
+        Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+    }
+    let wscontexts = data.get_ref().streams.iter().map(|(wsurl, streamdef)| (wsurl.clone(), streamdef.clone())).collect();
+    ws_start(websocketservice::WebsocketService::multiplexed(req.path().to_string(), wscontexts), websocketservice::OutputFormat::Json, &req, stream)
+}
+
+// the part of the glass-to-glass latency added by the server, in ms: a frame is received in about a frame
+// interval, processed, then held by the jitter buffer. The camera encoding, network and client decoding are not known.
+fn target_latency(stream: &StreamsDef) -> serde_json::Value {
+    let frame_interval = match (stream.gop.avg_interval, stream.gop.avg_frames) {
+        (Some(interval), Some(frames)) if frames > 0.0 => Some(interval.as_secs_f64() * 1000.0 / frames),
+        _ => None,
+    };
+    let processing = stream.latency.avg().map(|avg| avg.as_secs_f64() * 1000.0);
+    let buffer = stream.buffer.as_secs_f64() * 1000.0;
+    json!({
+        "low_latency": stream.low_latency,
+        "frame_interval": frame_interval,
+        "processing": processing,
+        "buffer": buffer,
+        "estimated": frame_interval.unwrap_or_default() + processing.unwrap_or_default() + buffer,
+    })
+}
+
+fn stream_json(stream: &StreamsDef) -> serde_json::Value {
+    json!({
+        "count": stream.count,
+        "state": stream.state.as_str(),
+        "failure": stream.failure.map(|f| f.as_str()),
+        "clock_skew": stream.clock_skew,
+        "codec": stream.codec,
+        "degraded": stream.degraded,
+        "cfg_error": stream.cfg_error,
+        "rtp": {
+            "frames": stream.rtp.frames,
+            "packets": stream.rtp.packets,
+            "lost": stream.rtp.lost,
+            "total_frames": stream.rtp_total.frames,
+            "total_packets": stream.rtp_total.packets,
+            "total_lost": stream.rtp_total.lost,
+        },
+        "latency": stream.latency.avg().map(|avg| json!({
+            "min": stream.latency.min.as_secs_f64() * 1000.0,
+            "avg": avg.as_secs_f64() * 1000.0,
+            "max": stream.latency.max.as_secs_f64() * 1000.0,
+        })),
+        "target_latency": target_latency(stream),
+        "gop": {
+            "avg_interval": stream.gop.avg_interval.map(|avg| avg.as_secs_f64()),
+            "avg_frames": stream.gop.avg_frames,
+            "current_gap": stream.gop.current_gap().map(|gap| gap.as_secs_f64()),
+            "overdue": stream.gop.overdue(),
+        },
+    })
+}
+
+// GET <streams path>
 async fn streams(data: web::Data<appcontext::AppContext>) -> HttpResponse {
     let app_context = data.get_ref();
     let mut data = json!({});
     for (key, streamdef) in &app_context.streams {
-        data[key] = json!({
-            "count": streamdef.lock().unwrap().count,
-        });
+        data[key] = stream_json(&streamdef.lock().unwrap());
+        data[key]["path"] = app_context.routes.public(&app_context.routes.websocket(key)).into();
     }
 
     HttpResponse::Ok().json(data)
 }
 
-#[get("/api/version")]
+fn find_stream<'a>(app_context: &'a appcontext::AppContext, name: &str) -> Option<&'a Arc<Mutex<StreamsDef>>> {
+    app_context.streams.get(&("/".to_string() + name))
+}
+
+// GET <streams path>/{name}/sse, the metadata of the broadcasted messages as Server-Sent Events, without the frames
+async fn stream_sse(req: HttpRequest, name: web::Path<String>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    let rx = match find_stream(data.get_ref(), &name) {
+        Some(streamdef) => {
+            let stream = streamdef.lock().unwrap();
+            if !check_token(&stream, &req) {
+                return HttpResponse::Unauthorized().finish();
+            }
+            if data.reject_paused && *stream.pause.borrow() {
+                return HttpResponse::ServiceUnavailable().body("stream is paused");
+            }
+            stream.rx.resubscribe()
+        },
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let events = BroadcastStream::new(rx).map(|msg| {
+        let event = match msg {
+            Ok(frame) => format!("data: {}\n\n", frame.metadata),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => format!("event: lagged\ndata: {}\n\n", json!({ "skipped": skipped })),
+        };
+        Ok::<_, actix_web::Error>(web::Bytes::from(event))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(events)
+}
+
+// GET <streams path>/{name}/keyframe
+async fn stream_keyframe(req: HttpRequest, name: web::Path<String>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    match find_stream(data.get_ref(), &name) {
+        Some(streamdef) => {
+            let stream = streamdef.lock().unwrap();
+            if !check_token(&stream, &req) {
+                return HttpResponse::Unauthorized().finish();
+            }
+            match &stream.last_keyframe {
+                Some(keyframe) => HttpResponse::Ok().content_type("application/octet-stream").body(keyframe.data.clone()),
+                None => HttpResponse::ServiceUnavailable().finish(),
+            }
+        },
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// GET <streams path>/{name}/parameters
+async fn stream_parameters(name: web::Path<String>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    let streamdef = match find_stream(data.get_ref(), &name) {
+        Some(streamdef) => streamdef.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let stream = streamdef.lock().unwrap();
+    let codec = match &stream.codec {
+        Some(codec) if !stream.cfg.is_empty() => codec,
+        _ => return HttpResponse::ServiceUnavailable().finish(),
+    };
+    let mut parameters = json!({
+        "codec": codec,
+        "extra_data": (!stream.extra_data.is_empty()).then(|| rtspclient::hex(&stream.extra_data)),
+        "cfg": rtspclient::hex(&stream.cfg),
+        "cfg_error": stream.cfg_error,
+        "width": stream.dimensions.map(|(width, _)| width),
+        "height": stream.dimensions.map(|(_, height)| height),
+    });
+    // avc1.PPCCLL: profile_idc, constraint flags and level_idc
+    if let Some(idc) = codec.strip_prefix("avc1.").filter(|idc| idc.len() == 6) {
+        let byte = |i: usize| u8::from_str_radix(&idc[i..i + 2], 16).ok();
+        parameters["profile_idc"] = byte(0).into();
+        parameters["constraint_flags"] = byte(2).into();
+        parameters["level"] = byte(4).map(|level| level as f64 / 10.0).into();
+    }
+    HttpResponse::Ok().json(parameters)
+}
+
+// GET <streams path>/{name}/events
+async fn stream_events(name: web::Path<String>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    match find_stream(data.get_ref(), &name) {
+        Some(streamdef) => {
+            let events: Vec<_> = streamdef.lock().unwrap().events.iter().map(|event| json!({
+                "time": event.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                "level": event.level.as_str(),
+                "message": event.message,
+            })).collect();
+            HttpResponse::Ok().json(events)
+        },
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// authenticated by --api-token
+fn set_paused(req: &HttpRequest, name: &str, data: &appcontext::AppContext, paused: bool) -> HttpResponse {
+    if !api_authorized(req, data) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match find_stream(data, name) {
+        Some(streamdef) => {
+            let stream = streamdef.lock().unwrap();
+            stream.pause.send_replace(paused);
+            HttpResponse::Ok().json(json!({ "paused": paused }))
+        },
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// POST <streams path>/{name}/pause
+async fn stream_pause(req: HttpRequest, name: web::Path<String>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    set_paused(&req, &name, data.get_ref(), true)
+}
+
+// POST <streams path>/{name}/resume
+async fn stream_resume(req: HttpRequest, name: web::Path<String>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    set_paused(&req, &name, data.get_ref(), false)
+}
+
+// POST <streams path>/{name}/webrtc, WHEP offer in the body
+#[cfg(feature = "webrtc")]
+async fn stream_webrtc(req: HttpRequest, name: web::Path<String>, offer: String, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    let streamdef = match find_stream(data.get_ref(), &name) {
+        Some(streamdef) => streamdef.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if !check_token(&streamdef.lock().unwrap(), &req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match webrtcservice::whep(streamdef, offer).await {
+        Ok(answer) => HttpResponse::Created().content_type("application/sdp").body(answer),
+        Err(err) => {
+            warn!("WebRTC negotiation failed: {:?}", err);
+            HttpResponse::BadRequest().body(err.to_string())
+        },
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// GET / when the player is not deployed in ./www
+async fn index_fallback(data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    let routes = &data.routes;
+    let mut keys: Vec<_> = data.streams.keys().collect();
+    keys.sort();
+    let streams = if data.control_only {
+        "<p>This instance only serves the api.</p>".to_string()
+    } else {
+        format!(
+            "<p>No player is deployed in ./www, the streams are available on these websockets:</p><ul>{}</ul>",
+            keys.iter().map(|key| format!("<li>{}</li>", html_escape(&routes.public(&routes.websocket(key))))).collect::<String>(),
+        )
+    };
+    let body = format!(
+        "<!DOCTYPE html><html><head><title>rtsp2web</title></head><body>{}\
+        <p><a href=\"{}\">streams</a> <a href=\"{}\">api</a></p></body></html>",
+        streams,
+        html_escape(&routes.public(&routes.streams)),
+        html_escape(&routes.public("/api/openapi.json")),
+    );
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(body)
+}
+
+// GET <streams path>/{name}
+async fn stream_info(name: web::Path<String>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    match find_stream(data.get_ref(), &name) {
+        Some(streamdef) => {
+            let stream = streamdef.lock().unwrap();
+            let mut data = stream_json(&stream);
+            if let Some((message, time)) = &stream.last_error {
+                data["last_error"] = json!({
+                    "message": message,
+                    "time": time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                });
+            }
+            HttpResponse::Ok().json(data)
+        },
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// each stream produced a keyframe with its parameter sets since startup
+#[get("/api/selftest")]
+async fn selftest(data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    let mut report = json!({});
+    let mut success = true;
+    for (key, streamdef) in &data.get_ref().streams {
+        let stream = streamdef.lock().unwrap();
+        let keyframe = stream.last_keyframe.is_some();
+        let cfg = rtspclient::has_parameter_sets(&stream.cfg) && stream.cfg_error.is_none();
+        success &= keyframe && cfg;
+        report[key] = json!({
+            "pass": keyframe && cfg,
+            "keyframe": keyframe,
+            "cfg": cfg,
+            "last_keyframe": stream.last_keyframe_time.map(|time| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+        });
+    }
+    if success {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+// GET <version path>
 async fn version() -> HttpResponse {
     let data = json!("version");
 
     HttpResponse::Ok().json(data)
 }
 
+#[get("/api/openapi.json")]
+async fn openapi_doc(data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    HttpResponse::Ok().json(openapi::document(data.get_ref()))
+}
+
+#[get("/metrics")]
+async fn metrics_text(data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics::prometheus(data.get_ref()))
+}
+
+#[get("/api/metrics.json")]
+async fn metrics_json(data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    HttpResponse::Ok().json(metrics::json(data.get_ref()))
+}
+
+fn log_filters_json() -> serde_json::Value {
+    json!({
+        "filters": logger::filters(),
+        "max_level": log::max_level().as_str(),
+    })
+}
+
+// authenticated by --api-token, as the PUT
+#[get("/api/loglevel")]
+async fn log_filters(req: HttpRequest, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    if !api_authorized(&req, data.get_ref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(log_filters_json())
+}
+
+// PUT /api/loglevel with {"filters": "info,rtsp2web_rs::rtspclient=debug"}, authenticated by --api-token
+#[put("/api/loglevel")]
+async fn set_log_filters(req: HttpRequest, body: web::Json<serde_json::Value>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
+    if !api_authorized(&req, data.get_ref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match body["filters"].as_str() {
+        Some(filters) => {
+            info!("log filters set to {}", filters);
+            logger::set_filters(filters);
+            HttpResponse::Ok().json(log_filters_json())
+        },
+        None => HttpResponse::BadRequest().body("missing \"filters\""),
+    }
+}
+
+// setting the level with ?level= is authenticated by --api-token
 #[get("/api/log")]
-async fn logger_level(query: web::Query<HashMap<String, String>>) -> HttpResponse {
+async fn logger_level(req: HttpRequest, query: web::Query<HashMap<String, String>>, data: web::Data<appcontext::AppContext>) -> HttpResponse {
     
     if let Some(level_str) = query.get("level") {
+        // without --api-token the level is set without authentication, as before the token existed
+        if data.api_token.is_some() && !api_authorized(&req, data.get_ref()) {
+            return HttpResponse::Unauthorized().finish();
+        }
         match level_str.as_str() {
             "Off" => log::set_max_level(log::LevelFilter::Off),
             "Error" => log::set_max_level(log::LevelFilter::Error),
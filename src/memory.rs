@@ -0,0 +1,79 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// global memory budget, all the streams broadcast only keyframes while the process gets close to it and the
+// websocket clients lagging meanwhile are disconnected
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{info, warn};
+use tokio::sync::watch;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// percentages of the budget entering and leaving the pressure state
+const PRESSURE_ON: u64 = 90;
+const PRESSURE_OFF: u64 = 75;
+
+pub struct MemoryBudget {
+    pub limit: u64,
+    usage: AtomicU64,
+    pressure: AtomicBool,
+}
+
+impl MemoryBudget {
+    pub fn new(limit_mb: u64) -> Self {
+        Self { limit: limit_mb * 1024 * 1024, usage: AtomicU64::new(0), pressure: AtomicBool::new(false) }
+    }
+
+    pub fn usage(&self) -> u64 {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    pub fn under_pressure(&self) -> bool {
+        self.pressure.load(Ordering::Relaxed)
+    }
+
+    fn update(&self, usage: u64) {
+        self.usage.store(usage, Ordering::Relaxed);
+        let pressure = self.under_pressure();
+        if !pressure && usage * 100 >= self.limit * PRESSURE_ON {
+            warn!("memory usage {} MB close to the {} MB budget, broadcasting only keyframes", usage >> 20, self.limit >> 20);
+            self.pressure.store(true, Ordering::Relaxed);
+        } else if pressure && usage * 100 < self.limit * PRESSURE_OFF {
+            info!("memory usage {} MB recovered, broadcasting all the frames", usage >> 20);
+            self.pressure.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+// resident set size of the process, only available on Linux
+pub fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+pub async fn run(budget: Arc<MemoryBudget>, mut shutdown: watch::Receiver<bool>) {
+    if rss().is_none() {
+        warn!("memory usage is not available on this system, --max-memory-mb is ignored");
+        return;
+    }
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = shutdown.changed() => break,
+        }
+        if let Some(usage) = rss() {
+            budget.update(usage);
+        }
+    }
+}
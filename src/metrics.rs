@@ -0,0 +1,129 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use serde_json::{json, Value};
+
+use crate::appcontext::AppContext;
+use crate::memory::MemoryBudget;
+use crate::streamdef::StreamsDef;
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// suffix of the sample name and its value
+type Sample<T> = (&'static str, fn(&T) -> Option<f64>);
+
+// a metric family, its samples are suffixed from its name (the sum and count of a summary)
+struct Metric<T: 'static> {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: &'static [Sample<T>],
+}
+
+const PREFIX: &str = "rtsp2web_";
+
+// a sample per stream, shared by the Prometheus and JSON formats
+const STREAM_METRICS: &[Metric<StreamsDef>] = &[
+    Metric { name: "rtsp2web_clients", kind: "gauge", help: "connected websocket clients",
+        samples: &[("", |stream| Some(stream.count as f64))] },
+    Metric { name: "rtsp2web_frame_latency_min_seconds", kind: "gauge", help: "minimum time between frame receipt and broadcast",
+        samples: &[("", |stream| stream.latency.avg().map(|_| stream.latency.min.as_secs_f64()))] },
+    Metric { name: "rtsp2web_frame_latency_max_seconds", kind: "gauge", help: "maximum time between frame receipt and broadcast",
+        samples: &[("", |stream| stream.latency.avg().map(|_| stream.latency.max.as_secs_f64()))] },
+    Metric { name: "rtsp2web_frame_latency_seconds", kind: "summary", help: "time between frame receipt and broadcast",
+        samples: &[("_sum", |stream| Some(stream.latency.sum.as_secs_f64())), ("_count", |stream| Some(stream.latency.count as f64))] },
+    Metric { name: "rtsp2web_oversized_frames_total", kind: "counter", help: "frames dropped for exceeding --max-frame-bytes",
+        samples: &[("", |stream| Some(stream.oversized_frames as f64))] },
+    Metric { name: "rtsp2web_degraded", kind: "gauge", help: "1 while only keyframes are broadcasted because clients are lagging",
+        samples: &[("", |stream| Some(if stream.degraded { 1.0 } else { 0.0 }))] },
+    Metric { name: "rtsp2web_lagged_frames_total", kind: "counter", help: "frames skipped by websocket clients falling behind",
+        samples: &[("", |stream| Some(stream.lagged_frames as f64))] },
+    Metric { name: "rtsp2web_frames_received_total", kind: "counter", help: "video frames depacketized from the RTP packets of the camera, not counted with --raw-rtp",
+        samples: &[("", |stream| Some(stream.rtp_total.frames as f64))] },
+    Metric { name: "rtsp2web_rtp_packets_received_total", kind: "counter", help: "RTP packets forwarded with --raw-rtp, the packets of the depacketized frames are not exposed by retina",
+        samples: &[("", |stream| Some(stream.rtp_total.packets as f64))] },
+    Metric { name: "rtsp2web_rtp_lost_packets_total", kind: "counter", help: "RTP packets lost according to the sequence numbers, out of order UDP packets are dropped by retina and counted as lost",
+        samples: &[("", |stream| Some(stream.rtp_total.lost as f64))] },
+    Metric { name: "rtsp2web_loss_reconnects_total", kind: "counter", help: "RTSP sessions reconnected after UDP packet loss with --recover-on-loss",
+        samples: &[("", |stream| Some(stream.loss_reconnects as f64))] },
+];
+
+// process wide samples, when a memory budget is set
+const MEMORY_METRICS: &[Metric<MemoryBudget>] = &[
+    Metric { name: "rtsp2web_memory_usage_bytes", kind: "gauge", help: "resident memory of the process",
+        samples: &[("", |memory| Some(memory.usage() as f64))] },
+    Metric { name: "rtsp2web_memory_limit_bytes", kind: "gauge", help: "memory budget set by --max-memory-mb",
+        samples: &[("", |memory| Some(memory.limit as f64))] },
+    Metric { name: "rtsp2web_memory_pressure", kind: "gauge", help: "1 while all the streams broadcast only keyframes to stay within the memory budget",
+        samples: &[("", |memory| Some(if memory.under_pressure() { 1.0 } else { 0.0 }))] },
+];
+
+fn sorted_streams(app_context: &AppContext) -> Vec<(&String, &Arc<Mutex<StreamsDef>>)> {
+    let mut streams: Vec<_> = app_context.streams.iter().collect();
+    streams.sort_by_key(|(key, _)| *key);
+    streams
+}
+
+// Prometheus text exposition format
+pub fn prometheus(app_context: &AppContext) -> String {
+    let mut out = String::new();
+    let streams = sorted_streams(app_context);
+    for metric in STREAM_METRICS {
+        header(&mut out, metric.name, metric.kind, metric.help);
+        for (suffix, value) in metric.samples {
+            for (key, streamdef) in &streams {
+                if let Some(value) = value(&streamdef.lock().unwrap()) {
+                    let _ = writeln!(out, "{}{}{{stream=\"{}\"}} {}", metric.name, suffix, key, value);
+                }
+            }
+        }
+    }
+    if let Some(memory) = &app_context.memory {
+        for metric in MEMORY_METRICS {
+            header(&mut out, metric.name, metric.kind, metric.help);
+            for (suffix, value) in metric.samples {
+                if let Some(value) = value(memory) {
+                    let _ = writeln!(out, "{}{} {}", metric.name, suffix, value);
+                }
+            }
+        }
+    }
+    out
+}
+
+// the same samples by stream, named without the rtsp2web_ prefix
+pub fn json(app_context: &AppContext) -> Value {
+    let mut streams = json!({});
+    for (key, streamdef) in sorted_streams(app_context) {
+        let stream = streamdef.lock().unwrap();
+        let mut values = json!({});
+        for metric in STREAM_METRICS {
+            for (suffix, value) in metric.samples {
+                values[format!("{}{}", metric.name.trim_start_matches(PREFIX), suffix)] = value(&stream).into();
+            }
+        }
+        streams[key] = values;
+    }
+    let mut out = json!({ "streams": streams });
+    if let Some(memory) = &app_context.memory {
+        let mut values = json!({});
+        for metric in MEMORY_METRICS {
+            for (suffix, value) in metric.samples {
+                values[format!("{}{}", metric.name.trim_start_matches(PREFIX), suffix)] = value(memory).into();
+            }
+        }
+        out["memory"] = values;
+    }
+    out
+}
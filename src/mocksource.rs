@@ -0,0 +1,184 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// synthetic H.264 source used with mock: urls and by the tests to exercise the pipeline without a camera.
+// The parameter sets are valid but the slices are filler, the video is not decodable.
+
+use crate::rtspclient::VideoSample;
+
+pub const CODEC: &str = "avc1.42c01e";
+pub const DIMENSIONS: (u32, u32) = (320, 240);
+pub const CLOCK_RATE: u32 = 90000;
+pub const FPS: u32 = 25;
+
+const GOP: u64 = 25;
+const NEW_PARAMETERS_PERIOD: u64 = 100;
+const MALFORMED_PERIOD: u64 = 50;
+const SEI_ONLY_PERIOD: u64 = 40;
+
+pub struct MockFrame {
+    pub timestamp: i64,
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+    pub new_parameters: bool,
+}
+
+impl VideoSample for MockFrame {
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+    fn is_random_access_point(&self) -> bool {
+        self.keyframe
+    }
+    fn has_new_parameters(&self) -> bool {
+        self.new_parameters
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn bit(&mut self, b: bool) {
+        if self.bits.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if b {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    fn u(&mut self, n: u32, value: u32) {
+        for i in (0..n).rev() {
+            self.bit(value >> i & 1 == 1);
+        }
+    }
+
+    fn ue(&mut self, value: u32) {
+        let v = value + 1;
+        let len = 32 - v.leading_zeros();
+        self.u(len - 1, 0);
+        self.u(len, v);
+    }
+
+    fn se(&mut self, value: i32) {
+        self.ue(if value > 0 { 2 * value as u32 - 1 } else { (-2 * value) as u32 });
+    }
+
+    // rbsp trailing bits and emulation prevention
+    fn into_nal(mut self, header: u8) -> Vec<u8> {
+        self.bit(true);
+        let mut nal = vec![header];
+        let mut zeros = 0;
+        for b in self.bytes {
+            if zeros == 2 && b <= 3 {
+                nal.push(3);
+                zeros = 0;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            nal.push(b);
+        }
+        nal
+    }
+}
+
+fn sps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.u(8, 66); // profile_idc baseline
+    w.u(8, 0xc0); // constraint_set0/1
+    w.u(8, 30); // level_idc
+    w.ue(0); // seq_parameter_set_id
+    w.ue(0); // log2_max_frame_num_minus4
+    w.ue(2); // pic_order_cnt_type
+    w.ue(1); // max_num_ref_frames
+    w.bit(false); // gaps_in_frame_num_value_allowed_flag
+    w.ue(DIMENSIONS.0 / 16 - 1); // pic_width_in_mbs_minus1
+    w.ue(DIMENSIONS.1 / 16 - 1); // pic_height_in_map_units_minus1
+    w.bit(true); // frame_mbs_only_flag
+    w.bit(true); // direct_8x8_inference_flag
+    w.bit(false); // frame_cropping_flag
+    w.bit(false); // vui_parameters_present_flag
+    w.into_nal(0x67)
+}
+
+fn pps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(0); // pic_parameter_set_id
+    w.ue(0); // seq_parameter_set_id
+    w.bit(false); // entropy_coding_mode_flag
+    w.bit(false); // bottom_field_pic_order_in_frame_present_flag
+    w.ue(0); // num_slice_groups_minus1
+    w.ue(0); // num_ref_idx_l0_default_active_minus1
+    w.ue(0); // num_ref_idx_l1_default_active_minus1
+    w.bit(false); // weighted_pred_flag
+    w.u(2, 0); // weighted_bipred_idc
+    w.se(0); // pic_init_qp_minus26
+    w.se(0); // pic_init_qs_minus26
+    w.se(0); // chroma_qp_index_offset
+    w.bit(true); // deblocking_filter_control_present_flag
+    w.bit(false); // constrained_intra_pred_flag
+    w.bit(false); // redundant_pic_cnt_present_flag
+    w.into_nal(0x68)
+}
+
+// parameter sets in Annex-B, as extract_config returns them
+pub fn config() -> Vec<u8> {
+    let mut cfg = vec![];
+    for nal in [sps(), pps()] {
+        cfg.extend_from_slice(&[0, 0, 0, 1]);
+        cfg.extend_from_slice(&nal);
+    }
+    cfg
+}
+
+fn push_avcc(data: &mut Vec<u8>, nal: &[u8]) {
+    data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+    data.extend_from_slice(nal);
+}
+
+#[derive(Default)]
+pub struct MockSource {
+    count: u64,
+}
+
+impl MockSource {
+    pub fn next_frame(&mut self) -> MockFrame {
+        let n = self.count;
+        self.count += 1;
+
+        let keyframe = n.is_multiple_of(GOP);
+        let mut data = vec![];
+        if n % SEI_ONLY_PERIOD == SEI_ONLY_PERIOD - 1 {
+            push_avcc(&mut data, &[0x06, 0x05, 0x01, 0x00, 0x80]);
+        } else {
+            let mut slice = vec![if keyframe { 0x65 } else { 0x41 }];
+            slice.extend((0..64).map(|i| (i as u64 * 31 + n) as u8 | 1));
+            push_avcc(&mut data, &slice);
+        }
+        if n % MALFORMED_PERIOD == MALFORMED_PERIOD - 1 {
+            // length prefix longer than the remaining data
+            data.extend_from_slice(&1000u32.to_be_bytes());
+            data.push(0x41);
+        }
+
+        MockFrame {
+            timestamp: (n * (CLOCK_RATE / FPS) as u64) as i64,
+            data,
+            keyframe,
+            new_parameters: n > 0 && n.is_multiple_of(NEW_PARAMETERS_PERIOD),
+        }
+    }
+}
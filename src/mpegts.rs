@@ -0,0 +1,400 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// minimal MPEG-TS muxer for a H.264 stream and its AAC audio, as expected by mpegts.js
+
+use std::time::Instant;
+
+use crate::streamdef::DataFrame;
+
+const PACKET_SIZE: usize = 188;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_TYPE_AAC: u8 = 0x0f;
+const TIMESCALE: f64 = 90000.0;
+// PTS ahead of the PCR, leaves the decoder time to buffer
+const PTS_DELAY: u64 = 9000;
+const AUD: [u8; 6] = [0, 0, 0, 1, 0x09, 0xf0];
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data {
+        crc ^= (*b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn pcr_bytes(base: u64) -> [u8; 6] {
+    [(base >> 25) as u8, (base >> 17) as u8, (base >> 9) as u8, (base >> 1) as u8, ((base & 1) << 7) as u8 | 0x7e, 0]
+}
+
+fn pts_bytes(pts: u64) -> [u8; 5] {
+    [
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        ((pts >> 14) & 0xfe) as u8 | 1,
+        (pts >> 7) as u8,
+        ((pts << 1) & 0xfe) as u8 | 1,
+    ]
+}
+
+// table section with its syntax header and crc
+fn section(table_id: u8, id: u16, version: u8, body: &[u8]) -> Vec<u8> {
+    let length = 5 + body.len() + 4;
+    let mut section = vec![table_id, 0xb0 | (length >> 8) as u8, length as u8, (id >> 8) as u8, id as u8, 0xc1 | (version & 0x1f) << 1, 0, 0];
+    section.extend_from_slice(body);
+    section.extend_from_slice(&crc32(&section).to_be_bytes());
+    section
+}
+
+// fixed header fields of the ADTS frames, from the AudioSpecificConfig of the stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Adts {
+    profile: u8,
+    frequency: u8,
+    channels: u8,
+}
+
+impl Adts {
+    // AAC object types 1 to 4 with an explicit sampling frequency index, the ones ADTS can carry
+    fn from_config(config: &[u8]) -> Option<Self> {
+        let object_type = config.first()? >> 3;
+        let frequency = (config[0] & 0x07) << 1 | config.get(1)? >> 7;
+        let channels = (config[1] >> 3) & 0x0f;
+        (matches!(object_type, 1..=4) && frequency < 13).then_some(Adts { profile: object_type - 1, frequency, channels })
+    }
+
+    fn header(&self, payload_len: usize) -> [u8; 7] {
+        let len = 7 + payload_len;
+        [
+            0xff, 0xf1,
+            self.profile << 6 | self.frequency << 2 | self.channels >> 2,
+            (self.channels & 3) << 6 | (len >> 11) as u8 & 0x03,
+            (len >> 3) as u8,
+            ((len & 7) as u8) << 5 | 0x1f,
+            0xfc,
+        ]
+    }
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    (0..value.len()).step_by(2).map(|i| value.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect()
+}
+
+#[derive(Default)]
+pub struct Muxer {
+    // PAT, PMT, video and audio
+    continuity: [u8; 4],
+    started: bool,
+    clock: Option<Instant>,
+    // each clock is aligned on the arrival of its first frame, the RTP timestamps of the video and audio have random origins
+    video_offset: Option<i64>,
+    audio_offset: Option<i64>,
+    // the audio is declared in the PMT from the keyframe following its first frame
+    audio: Option<Adts>,
+    audio_declared: bool,
+    // body and version of the last PMT
+    pmt: Option<(Vec<u8>, u8)>,
+}
+
+impl Muxer {
+    fn packetize(&mut self, out: &mut Vec<u8>, index: usize, pid: u16, payload: &[u8], mut adaptation: Vec<u8>) {
+        let mut remaining = payload;
+        let mut first = true;
+        while first || !remaining.is_empty() {
+            let mut has_adaptation = !adaptation.is_empty();
+            let space = PACKET_SIZE - 4 - if has_adaptation { 1 + adaptation.len() } else { 0 };
+            if remaining.len() < space {
+                // fill the packet with adaptation field stuffing
+                let mut stuffing = PACKET_SIZE - 4 - 1 - adaptation.len() - remaining.len();
+                if adaptation.is_empty() && stuffing > 0 {
+                    adaptation.push(0);
+                    stuffing -= 1;
+                }
+                adaptation.resize(adaptation.len() + stuffing, 0xff);
+                has_adaptation = true;
+            }
+            let cc = self.continuity[index];
+            self.continuity[index] = (cc + 1) & 0x0f;
+            out.push(0x47);
+            out.push((if first { 0x40 } else { 0 }) | ((pid >> 8) as u8 & 0x1f));
+            out.push(pid as u8);
+            out.push((if has_adaptation { 0x30 } else { 0x10 }) | cc);
+            if has_adaptation {
+                out.push(adaptation.len() as u8);
+                out.extend_from_slice(&adaptation);
+            }
+            let size = remaining.len().min(PACKET_SIZE - 4 - if has_adaptation { 1 + adaptation.len() } else { 0 });
+            out.extend_from_slice(&remaining[..size]);
+            remaining = &remaining[size..];
+            adaptation.clear();
+            first = false;
+        }
+    }
+
+    fn psi(&mut self, out: &mut Vec<u8>, index: usize, pid: u16, section: Vec<u8>) {
+        let mut payload = vec![0];
+        payload.extend(section);
+        payload.resize(PACKET_SIZE - 4, 0xff);
+        self.packetize(out, index, pid, &payload, vec![]);
+    }
+
+    fn tables(&mut self, out: &mut Vec<u8>) {
+        let pat = section(0x00, 1, 0, &[0, 1, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8]);
+        self.psi(out, 0, 0, pat);
+        // the PCR is carried by the video
+        let mut body = vec![0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, 0xf0, 0];
+        body.extend_from_slice(&[STREAM_TYPE_H264, 0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, 0xf0, 0]);
+        if self.audio_declared {
+            body.extend_from_slice(&[STREAM_TYPE_AAC, 0xe0 | (AUDIO_PID >> 8) as u8, AUDIO_PID as u8, 0xf0, 0]);
+        }
+        let version = match &self.pmt {
+            Some((previous, version)) if *previous != body => (version + 1) & 0x1f,
+            Some((_, version)) => *version,
+            None => 0,
+        };
+        let pmt = section(0x02, 1, version, &body);
+        self.pmt = Some((body, version));
+        self.psi(out, 1, PMT_PID, pmt);
+    }
+
+    // 90kHz clock of a frame from its timestamp at the clock rate, or the arrival time when it is not sent
+    fn clock(&mut self, ts: Option<f64>, clock_rate: f64, audio: bool) -> u64 {
+        let now = (self.clock.get_or_insert_with(Instant::now).elapsed().as_secs_f64() * TIMESCALE) as i64;
+        let Some(ts) = ts else {
+            return now as u64 & 0x1_ffff_ffff;
+        };
+        let ts = (ts / 1000.0 * TIMESCALE / clock_rate) as i64;
+        let offset = if audio { &mut self.audio_offset } else { &mut self.video_offset };
+        (ts + *offset.get_or_insert(now - ts)).rem_euclid(1 << 33) as u64
+    }
+
+    // PES of an AAC frame with its ADTS header, once the audio is declared
+    fn mux_audio(&mut self, frame: &DataFrame) -> Option<Vec<u8>> {
+        if !frame.metadata["codec"].as_str().is_some_and(|codec| codec.starts_with("mp4a")) {
+            return None;
+        }
+        let adts = Adts::from_config(&unhex(frame.metadata["config"].as_str()?)?)?;
+        self.audio = Some(adts);
+        if !self.started || !self.audio_declared {
+            return None;
+        }
+        let clock_rate = frame.metadata["clock_rate"].as_f64().filter(|rate| *rate > 0.0)?;
+        let pts = self.clock(frame.metadata["ts"].as_f64(), clock_rate, true);
+        let length = u16::try_from(3 + 5 + 7 + frame.data.len()).ok()?;
+        let mut pes = vec![0, 0, 1, 0xc0, (length >> 8) as u8, length as u8, 0x80, 0x80, 5];
+        pes.extend_from_slice(&pts_bytes((pts + PTS_DELAY) & 0x1_ffff_ffff));
+        pes.extend_from_slice(&adts.header(frame.data.len()));
+        pes.extend_from_slice(&frame.data);
+        let mut out = vec![];
+        self.packetize(&mut out, 3, AUDIO_PID, &pes, vec![]);
+        Some(out)
+    }
+
+    // TS packets of a broadcasted frame, nothing until the first keyframe
+    pub fn mux(&mut self, frame: &DataFrame) -> Option<Vec<u8>> {
+        if frame.metadata["media"] == "audio" {
+            return self.mux_audio(frame);
+        }
+        if frame.metadata["media"] != "video" {
+            return None;
+        }
+        let keyframe = match frame.metadata["type"].as_str() {
+            Some("keyframe") => true,
+            None => false,
+            _ => return None,
+        };
+        if !keyframe && !self.started {
+            return None;
+        }
+        self.started = true;
+
+        let base = self.clock(frame.metadata["ts"].as_f64(), TIMESCALE, false);
+
+        let mut out = vec![];
+        // tables are repeated on each keyframe, that carries the parameter sets
+        if keyframe {
+            self.audio_declared = self.audio.is_some();
+            self.tables(&mut out);
+        }
+
+        let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5];
+        pes.extend_from_slice(&pts_bytes((base + PTS_DELAY) & 0x1_ffff_ffff));
+        pes.extend_from_slice(&AUD);
+        pes.extend_from_slice(&frame.data);
+
+        let mut adaptation = vec![if keyframe { 0x50 } else { 0x10 }];
+        adaptation.extend_from_slice(&pcr_bytes(base));
+        self.packetize(&mut out, 2, VIDEO_PID, &pes, adaptation);
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct Packet {
+        pid: u16,
+        start: bool,
+        cc: u8,
+        adaptation: Option<Vec<u8>>,
+        payload: Vec<u8>,
+    }
+
+    fn packets(data: &[u8]) -> Vec<Packet> {
+        assert_eq!(data.len() % PACKET_SIZE, 0);
+        data.chunks(PACKET_SIZE).map(|p| {
+            assert_eq!(p[0], 0x47);
+            let (adaptation, payload) = match p[3] & 0x30 {
+                0x30 => (Some(p[5..5 + p[4] as usize].to_vec()), p[5 + p[4] as usize..].to_vec()),
+                _ => (None, p[4..].to_vec()),
+            };
+            Packet { pid: (p[1] as u16 & 0x1f) << 8 | p[2] as u16, start: p[1] & 0x40 != 0, cc: p[3] & 0x0f, adaptation, payload }
+        }).collect()
+    }
+
+    // the section of a PSI packet
+    fn section_of(packet: &Packet) -> &[u8] {
+        let length = ((packet.payload[2] as usize & 0x0f) << 8) | packet.payload[3] as usize;
+        &packet.payload[1..4 + length]
+    }
+
+    fn pts_of(bytes: &[u8]) -> u64 {
+        (bytes[0] as u64 & 0x0e) << 29 | (bytes[1] as u64) << 22 | (bytes[2] as u64 & 0xfe) << 14 | (bytes[3] as u64) << 7 | bytes[4] as u64 >> 1
+    }
+
+    fn video(keyframe: bool, ticks: u64) -> DataFrame {
+        let mut metadata = json!({ "media": "video", "ts": (ticks * 1000) as f64 });
+        if keyframe {
+            metadata["type"] = "keyframe".into();
+        }
+        DataFrame { metadata, data: vec![0, 0, 0, 1, if keyframe { 0x65 } else { 0x41 }, 0x88, 0x84].into() }
+    }
+
+    fn audio(samples: u64) -> DataFrame {
+        // AAC-LC, 16kHz, mono
+        let metadata = json!({ "media": "audio", "clock_rate": 16000, "codec": "mp4a.40.2", "config": "1408", "ts": (samples * 1000) as f64 });
+        DataFrame { metadata, data: vec![0x21, 0x10, 0x05].into() }
+    }
+
+    #[test]
+    fn tables_crc() {
+        assert_eq!(crc32(b"123456789"), 0x0376_e6e7);
+        let mut out = vec![];
+        Muxer::default().tables(&mut out);
+        let tables = packets(&out);
+        assert_eq!(tables.iter().map(|p| p.pid).collect::<Vec<_>>(), [0, PMT_PID]);
+        for packet in &tables {
+            // the crc of a section followed by its crc is zero
+            assert_eq!(crc32(section_of(packet)), 0);
+        }
+    }
+
+    #[test]
+    fn stuffing_fills_the_last_packet() {
+        for len in [1usize, 182, 183, 184, 185, 368, 369] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut out = vec![];
+            Muxer::default().packetize(&mut out, 2, VIDEO_PID, &payload, vec![]);
+            let packets = packets(&out);
+            assert_eq!(packets.len(), len.div_ceil(184), "{} bytes", len);
+            assert_eq!(packets.iter().flat_map(|p| p.payload.clone()).collect::<Vec<_>>(), payload, "{} bytes", len);
+            let last = packets.last().unwrap();
+            match len % 184 {
+                0 => assert!(last.adaptation.is_none()),
+                // an empty adaptation field, then only its flags
+                183 => assert_eq!(last.adaptation.as_deref(), Some(&[][..])),
+                182 => assert_eq!(last.adaptation.as_deref(), Some(&[0][..])),
+                remainder => assert_eq!(last.adaptation.as_ref().unwrap().len(), 183 - remainder),
+            }
+        }
+    }
+
+    #[test]
+    fn continuity_counters_per_pid() {
+        let mut muxer = Muxer::default();
+        let mut out = vec![];
+        for i in 0..40 {
+            out.extend(muxer.mux(&video(i % 2 == 0, 3600 * i)).unwrap());
+        }
+        let packets = packets(&out);
+        for pid in [0, PMT_PID, VIDEO_PID] {
+            let counters: Vec<u8> = packets.iter().filter(|p| p.pid == pid).map(|p| p.cc).collect();
+            assert!(counters.len() > 16);
+            assert!(counters.iter().enumerate().all(|(i, &cc)| cc == i as u8 & 0x0f), "pid {}: {:?}", pid, counters);
+        }
+    }
+
+    #[test]
+    fn pts_and_pcr_encoding() {
+        for pts in [0, 1, 90000, (1 << 32) + 12345, (1 << 33) - 1] {
+            let bytes = pts_bytes(pts);
+            assert_eq!(pts_of(&bytes), pts);
+            // marker bits
+            assert_eq!((bytes[0] & 0xf1, bytes[2] & 1, bytes[4] & 1), (0x21, 1, 1));
+            let pcr = pcr_bytes(pts);
+            assert_eq!((pcr[0] as u64) << 25 | (pcr[1] as u64) << 17 | (pcr[2] as u64) << 9 | (pcr[3] as u64) << 1 | pcr[4] as u64 >> 7, pts);
+        }
+
+        let mut muxer = Muxer::default();
+        let mut clocks = vec![];
+        for (keyframe, ticks) in [(true, 1_000_000), (false, 1_003_600)] {
+            let out = muxer.mux(&video(keyframe, ticks)).unwrap();
+            let packet = packets(&out).into_iter().find(|p| p.pid == VIDEO_PID && p.start).unwrap();
+            let adaptation = packet.adaptation.unwrap();
+            assert_eq!(adaptation[0] & 0x40 != 0, keyframe);
+            let base = (adaptation[1] as u64) << 25 | (adaptation[2] as u64) << 17 | (adaptation[3] as u64) << 9 | (adaptation[4] as u64) << 1 | adaptation[5] as u64 >> 7;
+            // flags and PCR, then the stuffing
+            assert!(adaptation.len() >= 7);
+            assert_eq!(packet.payload[..4], [0, 0, 1, 0xe0]);
+            assert_eq!(pts_of(&packet.payload[9..14]), base + PTS_DELAY);
+            clocks.push(base);
+        }
+        // the clock starts at the first frame
+        assert!(clocks[0] < 9000);
+        assert_eq!(clocks[1] - clocks[0], 3600);
+    }
+
+    #[test]
+    fn aac_declared_from_the_next_keyframe() {
+        let mut muxer = Muxer::default();
+        // nothing before the first keyframe, and the audio waits for its declaration in the PMT
+        assert!(muxer.mux(&video(false, 0)).is_none());
+        let first = packets(&muxer.mux(&video(true, 0)).unwrap());
+        assert_eq!(section_of(&first[1])[5], 0xc1);
+        assert!(muxer.mux(&audio(1024)).is_none());
+
+        let second = packets(&muxer.mux(&video(true, 3600)).unwrap());
+        let pmt = section_of(&second[1]);
+        // version 1, then the PCR pid, the video and the audio streams
+        assert_eq!(pmt[5], 0xc3);
+        assert_eq!(pmt[12..22], [STREAM_TYPE_H264, 0xe1, 0x00, 0xf0, 0, STREAM_TYPE_AAC, 0xe1, 0x01, 0xf0, 0]);
+        assert_eq!(crc32(pmt), 0);
+
+        let out = packets(&muxer.mux(&audio(2048)).unwrap());
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].pid, out[0].start, out[0].cc), (AUDIO_PID, true, 0));
+        let pes = &out[0].payload;
+        assert_eq!(pes[..4], [0, 0, 1, 0xc0]);
+        assert_eq!(u16::from_be_bytes([pes[4], pes[5]]) as usize, pes.len() - 6);
+        // LC profile, 16kHz and mono ADTS header of 7 bytes followed by the frame
+        assert_eq!(pes[14..21], [0xff, 0xf1, 0x60, 0x40, 0x01, 0x5f, 0xfc]);
+        assert_eq!(pes[21..], [0x21, 0x10, 0x05]);
+        // the PMT is not changed by the following keyframes
+        let third = packets(&muxer.mux(&video(true, 7200)).unwrap());
+        assert_eq!(section_of(&third[1])[5], 0xc3);
+    }
+}
@@ -0,0 +1,396 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use serde_json::{json, Value};
+
+use crate::appcontext::AppContext;
+use crate::websocketservice::OutputFormat;
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "200": {
+            "description": description,
+            "content": { "application/json": { "schema": schema } },
+        }
+    })
+}
+
+pub fn document(app_context: &AppContext) -> Value {
+    let routes = &app_context.routes;
+    let mut paths = json!({
+        "/api/log": {
+            "get": {
+                "summary": "get or set the log level, setting it requires the bearer token when --api-token is set",
+                "security": [{}, { "bearer": [] }],
+                "parameters": [{
+                    "name": "level",
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string", "enum": ["Off", "Error", "Warn", "Info", "Debug", "Trace"] },
+                }],
+                "responses": {
+                    "200": { "description": "current log level", "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "properties": { "level": { "type": "string" } },
+                    } } } },
+                    "401": { "description": "level set without the bearer token of --api-token" },
+                },
+            }
+        },
+        "/api/loglevel": {
+            "get": {
+                "summary": "log filters, in the RUST_LOG syntax, requires --api-token",
+                "security": [{ "bearer": [] }],
+                "responses": {
+                    "200": { "description": "current filters", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/LogFilters" } } } },
+                    "401": { "description": "missing or wrong bearer token, or no --api-token" },
+                },
+            },
+            "put": {
+                "summary": "replace the log filters, e.g. info,rtsp2web_rs::rtspclient=debug, requires --api-token",
+                "security": [{ "bearer": [] }],
+                "requestBody": { "content": { "application/json": { "schema": {
+                    "type": "object",
+                    "properties": { "filters": { "type": "string" } },
+                    "required": ["filters"],
+                } } } },
+                "responses": {
+                    "200": { "description": "new filters", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/LogFilters" } } } },
+                    "400": { "description": "missing filters" },
+                    "401": { "description": "missing or wrong bearer token, or no --api-token" },
+                },
+            }
+        },
+        "/api/selftest": {
+            "get": {
+                "summary": "whether each stream produced a keyframe with its parameter sets since startup",
+                "responses": {
+                    "200": { "description": "all the streams pass", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/SelfTest" } } } },
+                    "503": { "description": "at least one stream fails", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/SelfTest" } } } },
+                },
+            }
+        },
+        "/metrics": {
+            "get": {
+                "summary": "Prometheus metrics",
+                "responses": { "200": { "description": "text exposition format", "content": { "text/plain": {} } } },
+            }
+        },
+        "/api/metrics.json": {
+            "get": {
+                "summary": "the samples of /metrics named without the rtsp2web_ prefix, for JSON datasources",
+                "responses": json_response("samples by stream, and of the memory budget when set", json!({
+                    "type": "object",
+                    "properties": {
+                        "streams": { "type": "object", "additionalProperties": { "type": "object", "additionalProperties": { "type": "number", "nullable": true } } },
+                        "memory": { "type": "object", "additionalProperties": { "type": "number" } },
+                    },
+                })),
+            }
+        },
+        "/api/openapi.json": {
+            "get": {
+                "summary": "this document",
+                "responses": json_response("OpenAPI document", json!({ "type": "object" })),
+            }
+        },
+    });
+
+    paths[&routes.version] = json!({
+        "get": {
+            "summary": "server version",
+            "responses": json_response("version string", json!({ "type": "string" })),
+        }
+    });
+    // relative to the streams path
+    let streams = json!({
+        "": {
+            "get": {
+                "summary": "configured streams",
+                "responses": json_response("streams indexed by websocket path", json!({
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "object",
+                        "properties": {
+                            "path": { "type": "string", "description": "websocket path including the base path" },
+                            "count": { "type": "integer", "description": "connected websocket clients" },
+                            "state": { "type": "string", "enum": ["connecting", "playing", "reconnecting", "throttled", "failed", "paused", "idle", "ended"] },
+                            "failure": { "type": "string", "nullable": true, "enum": ["auth", "connect", "midstream", "unsupported"] },
+                            "clock_skew": { "type": "number", "nullable": true, "description": "camera clock minus server clock in seconds, from RTCP sender reports" },
+                            "codec": { "type": "string", "nullable": true, "description": "RFC 6381 codec, or the SDP encoding name of an unsupported video codec such as av1" },
+                            "degraded": { "type": "boolean", "description": "only keyframes are broadcasted because clients are lagging" },
+                            "cfg_error": { "type": "string", "nullable": true, "description": "why the parameter sets can't be parsed" },
+                            "rtp": { "$ref": "#/components/schemas/RtpStats" },
+                            "latency": { "$ref": "#/components/schemas/Latency" },
+                            "target_latency": { "$ref": "#/components/schemas/TargetLatency" },
+                            "gop": { "$ref": "#/components/schemas/Gop" },
+                        },
+                    },
+                })),
+            }
+        },
+        "/{name}": {
+            "get": {
+                "summary": "stream details",
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": json_response("stream state and last error", json!({
+                    "type": "object",
+                    "properties": {
+                        "count": { "type": "integer" },
+                        "state": { "type": "string" },
+                        "failure": { "type": "string", "nullable": true },
+                        "clock_skew": { "type": "number", "nullable": true },
+                        "codec": { "type": "string", "nullable": true },
+                        "degraded": { "type": "boolean" },
+                        "cfg_error": { "type": "string", "nullable": true },
+                        "rtp": { "$ref": "#/components/schemas/RtpStats" },
+                        "latency": { "$ref": "#/components/schemas/Latency" },
+                        "target_latency": { "$ref": "#/components/schemas/TargetLatency" },
+                        "gop": { "$ref": "#/components/schemas/Gop" },
+                        "last_error": {
+                            "type": "object",
+                            "properties": {
+                                "message": { "type": "string" },
+                                "time": { "type": "integer", "description": "seconds since epoch" },
+                            },
+                        },
+                    },
+                })),
+            }
+        },
+        "/{name}/keyframe": {
+            "get": {
+                "summary": "last keyframe in Annex-B with the parameter sets",
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }, { "name": "token", "in": "query", "required": false, "schema": { "type": "string" } }],
+                "responses": {
+                    "200": { "description": "H.264 bitstream", "content": { "application/octet-stream": {} } },
+                    "401": { "description": "missing or wrong token" },
+                    "503": { "description": "no keyframe received yet" },
+                },
+            }
+        },
+        "/{name}/pause": {
+            "post": {
+                "summary": "stop pulling the RTSP session of the stream, keeping it registered, requires --api-token",
+                "security": [{ "bearer": [] }],
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": {
+                    "200": { "description": "paused", "content": { "application/json": { "schema": { "type": "object", "properties": { "paused": { "type": "boolean" } } } } } },
+                    "401": { "description": "missing or wrong bearer token, or no --api-token" },
+                    "404": { "description": "unknown stream" },
+                },
+            }
+        },
+        "/{name}/resume": {
+            "post": {
+                "summary": "reconnect a paused stream, requires --api-token",
+                "security": [{ "bearer": [] }],
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": {
+                    "200": { "description": "paused", "content": { "application/json": { "schema": { "type": "object", "properties": { "paused": { "type": "boolean" } } } } } },
+                    "401": { "description": "missing or wrong bearer token, or no --api-token" },
+                    "404": { "description": "unknown stream" },
+                },
+            }
+        },
+        "/{name}/parameters": {
+            "get": {
+                "summary": "video parameters of the current session, to diagnose the parameter sets extraction",
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": {
+                    "200": {
+                        "description": "parameters",
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "properties": {
+                                "codec": { "type": "string" },
+                                "extra_data": { "type": "string", "nullable": true, "description": "hex of the parameters received from the camera, null for file: and mock: urls" },
+                                "cfg": { "type": "string", "description": "hex of the Annex-B parameter sets sent with the keyframes" },
+                                "cfg_error": { "type": "string", "nullable": true, "description": "why the SPS or PPS can't be parsed, null when valid" },
+                                "width": { "type": "integer", "nullable": true },
+                                "height": { "type": "integer", "nullable": true },
+                                "profile_idc": { "type": "integer" },
+                                "constraint_flags": { "type": "integer" },
+                                "level": { "type": "number" },
+                            },
+                        } } },
+                    },
+                    "404": { "description": "unknown stream" },
+                    "503": { "description": "no parameters received yet" },
+                },
+            }
+        },
+        "/{name}/sse": {
+            "get": {
+                "summary": "metadata of the broadcasted messages as Server-Sent Events, without the frames",
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }, { "name": "token", "in": "query", "required": false, "schema": { "type": "string" } }],
+                "responses": {
+                    "200": { "description": "data events with the JSON metadata, lagged events with the number of skipped messages", "content": { "text/event-stream": {} } },
+                    "401": { "description": "missing or wrong token" },
+                    "404": { "description": "unknown stream" },
+                    "503": { "description": "stream is paused, with --reject-paused" },
+                },
+            }
+        },
+        "/{name}/events": {
+            "get": {
+                "summary": "recent state changes and errors of the stream",
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": json_response("events, oldest first", json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "time": { "type": "integer", "description": "seconds since epoch" },
+                            "level": { "type": "string" },
+                            "message": { "type": "string" },
+                        },
+                    },
+                })),
+            }
+        },
+    });
+    for (path, item) in streams.as_object().unwrap() {
+        paths[format!("{}{}", routes.streams, path)] = item.clone();
+    }
+    #[cfg(feature = "webrtc")]
+    {
+        paths[format!("{}/{{name}}/webrtc", routes.streams)] = json!({
+            "post": {
+                "summary": "WHEP: answer a WebRTC offer and send the stream on its video track, host candidates only",
+                "parameters": [{ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }, { "name": "token", "in": "query", "required": false, "schema": { "type": "string" } }],
+                "requestBody": { "content": { "application/sdp": { "schema": { "type": "string" } } } },
+                "responses": {
+                    "201": { "description": "SDP answer", "content": { "application/sdp": { "schema": { "type": "string" } } } },
+                    "400": { "description": "invalid offer" },
+                    "401": { "description": "missing or wrong token" },
+                    "404": { "description": "unknown stream" },
+                },
+            }
+        });
+    }
+
+    let ws_protocol = |formats: &[OutputFormat]| json!({
+        "name": "Sec-WebSocket-Protocol",
+        "in": "header",
+        "required": false,
+        "schema": { "type": "string", "enum": formats.iter().map(|format| format.protocol()).collect::<Vec<_>>() },
+    });
+    let ws_format = json!({
+        "name": "format",
+        "in": "query",
+        "required": false,
+        "description": format!("format of the frames, else the first supported subprotocol requested, else {:?}", app_context.output_format).to_lowercase(),
+        "schema": { "type": "string", "enum": ["json", "annexb", "mpegts", "fmp4"] },
+    });
+    let ws_responses = json!({
+        "101": { "description": "switching protocols, the selected subprotocol is echoed" },
+        "400": { "description": "unknown format, or none of the requested subprotocols is the one of the format" },
+        "401": { "description": "missing or wrong token, /ws/all is refused when a stream requires one" },
+        "503": { "description": "the stream is paused and --reject-paused is set" },
+    });
+    for wsurl in app_context.streams.keys().filter(|_| !app_context.control_only) {
+        paths[routes.websocket(wsurl)] = json!({
+            "get": {
+                "summary": "websocket stream, each frame is a JSON metadata text message followed by a binary message in Annex-B, or MPEG-TS or fragmented MP4 binary messages",
+                "parameters": [ws_protocol(&OutputFormat::ALL), ws_format.clone(), { "name": "token", "in": "query", "required": false, "schema": { "type": "string" } }],
+                "responses": ws_responses.clone(),
+            }
+        });
+    }
+
+    if app_context.ws_all && !app_context.control_only {
+        paths["/ws/all"] = json!({
+            "get": {
+                "summary": "websocket forwarding all the streams, metadata messages are tagged with the stream name",
+                "parameters": [ws_protocol(&[OutputFormat::Json])],
+                "responses": ws_responses.clone(),
+            }
+        });
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": routes.public("/") }],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+            "schemas": {
+                "LogFilters": {
+                    "type": "object",
+                    "properties": {
+                        "filters": { "type": "string", "nullable": true },
+                        "max_level": { "type": "string" },
+                    },
+                },
+                "RtpStats": {
+                    "type": "object",
+                    "description": "reception counters of the current RTSP session and since startup, packets are only counted with --raw-rtp and frames without it",
+                    "properties": {
+                        "frames": { "type": "integer", "description": "depacketized video frames" },
+                        "packets": { "type": "integer", "description": "RTP packets forwarded with --raw-rtp" },
+                        "lost": { "type": "integer", "description": "lost packets, out of order UDP packets included" },
+                        "total_frames": { "type": "integer" },
+                        "total_packets": { "type": "integer" },
+                        "total_lost": { "type": "integer" },
+                    },
+                },
+                "SelfTest": {
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "object",
+                        "properties": {
+                            "pass": { "type": "boolean" },
+                            "keyframe": { "type": "boolean", "description": "a keyframe was received" },
+                            "cfg": { "type": "boolean", "description": "the parameter sets were extracted and parsed" },
+                            "last_keyframe": { "type": "integer", "nullable": true, "description": "seconds since epoch" },
+                        },
+                    },
+                },
+                "Latency": {
+                    "type": "object",
+                    "nullable": true,
+                    "description": "milliseconds between receiving a frame from the RTSP session and broadcasting it",
+                    "properties": {
+                        "min": { "type": "number" },
+                        "avg": { "type": "number" },
+                        "max": { "type": "number" },
+                    },
+                },
+                "TargetLatency": {
+                    "type": "object",
+                    "description": "estimated part of the glass-to-glass latency added by the server, in milliseconds",
+                    "properties": {
+                        "low_latency": { "type": "boolean" },
+                        "frame_interval": { "type": "number", "nullable": true, "description": "time to receive a frame, from the GOP stats" },
+                        "processing": { "type": "number", "nullable": true, "description": "average latency between receiving and broadcasting a frame" },
+                        "buffer": { "type": "number", "description": "--buffer-ms, 0 with low latency" },
+                        "estimated": { "type": "number", "description": "sum of the known components" },
+                    },
+                },
+                "Gop": {
+                    "type": "object",
+                    "description": "keyframe intervals of the current session, in seconds",
+                    "properties": {
+                        "avg_interval": { "type": "number", "nullable": true, "description": "moving average of the time between keyframes" },
+                        "avg_frames": { "type": "number", "nullable": true, "description": "moving average of the frames between keyframes" },
+                        "current_gap": { "type": "number", "nullable": true, "description": "time since the last keyframe" },
+                        "overdue": { "type": "boolean", "description": "the current gap exceeds twice the average interval" },
+                    },
+                },
+            },
+        },
+    })
+}
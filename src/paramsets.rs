@@ -0,0 +1,97 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// validation of the Annex-B parameter sets (cfg) sent before the keyframes, a decoder initialized
+// from a cfg without a parseable SPS and PPS fails on every frame
+
+use h264_reader::nal::pps::PicParameterSet;
+use h264_reader::nal::sps::SeqParameterSet;
+use h264_reader::nal::{Nal, RefNal};
+use h264_reader::Context;
+
+const MARKER: [u8; 4] = [0, 0, 0, 1];
+// larger than the 8192x4320 of the highest H.264 levels
+const MAX_DIMENSION: u32 = 16384;
+
+// NAL units of an Annex-B buffer, with 3 or 4 bytes start codes
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let starts: Vec<usize> = data.windows(3).enumerate().filter(|(_, w)| *w == [0, 0, 1]).map(|(i, _)| i + 3).collect();
+    starts.iter().enumerate().map(|(i, &start)| {
+        let end = starts.get(i + 1).map_or(data.len(), |next| next - 3);
+        let nal = &data[start..end.max(start)];
+        // the trailing zero bytes belong to the next start code
+        &nal[..nal.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1)]
+    }).filter(|nal| !nal.is_empty()).collect()
+}
+
+// the cfg rebuilt with 4 bytes start codes and without the trailing zero bytes of its NAL units, once its
+// SPS and PPS are parsed and the dimensions checked
+pub fn normalize(cfg: &[u8]) -> Result<Vec<u8>, String> {
+    let nals = nal_units(cfg);
+    let mut ctx = Context::default();
+    for nal in &nals {
+        let rbsp = RefNal::new(nal, &[], true);
+        match nal[0] & 0x1f {
+            7 => {
+                let sps = SeqParameterSet::from_bits(rbsp.rbsp_bits()).map_err(|e| format!("invalid SPS: {:?}", e))?;
+                let (width, height) = sps.pixel_dimensions().map_err(|e| format!("invalid SPS dimensions: {:?}", e))?;
+                if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+                    return Err(format!("SPS dimensions {}x{} out of range", width, height));
+                }
+                ctx.put_seq_param_set(sps);
+            },
+            8 => {
+                let pps = PicParameterSet::from_bits(&ctx, rbsp.rbsp_bits()).map_err(|e| format!("invalid PPS: {:?}", e))?;
+                ctx.put_pic_param_set(pps);
+            },
+            _ => {},
+        }
+    }
+    if ctx.sps().next().is_none() {
+        return Err("no SPS".to_string());
+    }
+    if ctx.pps().next().is_none() {
+        return Err("no PPS".to_string());
+    }
+    Ok(nals.iter().flat_map(|nal| MARKER.iter().chain(nal.iter())).copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 8] = [0x67, 0x42, 0xc0, 0x1e, 0xda, 0x05, 0x07, 0xe4];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter().flat_map(|nal| MARKER.iter().chain(nal.iter())).copied().collect()
+    }
+
+    #[test]
+    fn normalize_sps_then_pps() {
+        // 3 bytes start codes and trailing zero bytes
+        let cfg = [&[0, 0, 1][..], &SPS, &[0, 0, 0, 0, 1], &PPS, &[0, 0]].concat();
+        assert_eq!(normalize(&cfg).unwrap(), annex_b(&[&SPS, &PPS]));
+        // the PPS refers to a SPS not parsed yet
+        assert!(normalize(&annex_b(&[&PPS, &SPS])).unwrap_err().starts_with("invalid PPS"));
+    }
+
+    #[test]
+    fn normalize_keeps_duplicates() {
+        let cfg = annex_b(&[&SPS, &PPS, &SPS, &PPS]);
+        assert_eq!(normalize(&cfg).unwrap(), cfg);
+    }
+
+    #[test]
+    fn normalize_rejects_missing_parameter_sets() {
+        assert_eq!(normalize(&annex_b(&[&SPS])).unwrap_err(), "no PPS");
+        assert_eq!(normalize(&[]).unwrap_err(), "no SPS");
+        assert!(normalize(&annex_b(&[&SPS[..2], &PPS])).unwrap_err().starts_with("invalid SPS"));
+    }
+}
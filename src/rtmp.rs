@@ -0,0 +1,486 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// minimal RTMP publisher forwarding the H.264 frames of a stream as FLV video tags
+
+use anyhow::{anyhow, Error};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{broadcast, watch};
+
+use crate::fmp4;
+use crate::paramsets::nal_units;
+use crate::streamdef::DataFrame;
+
+const CHUNK_SIZE: usize = 4096;
+const HANDSHAKE_SIZE: usize = 1536;
+
+const MSG_SET_CHUNK_SIZE: u8 = 1;
+const MSG_VIDEO: u8 = 9;
+const MSG_COMMAND_AMF0: u8 = 20;
+
+const CSID_CONTROL: u8 = 2;
+const CSID_COMMAND: u8 = 3;
+const CSID_VIDEO: u8 = 6;
+
+enum Amf {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Vec<(String, Amf)>),
+    Null,
+}
+
+fn amf_encode_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn amf_encode(out: &mut Vec<u8>, value: &Amf) {
+    match value {
+        Amf::Number(n) => {
+            out.push(0x00);
+            out.extend_from_slice(&n.to_be_bytes());
+        },
+        Amf::Boolean(b) => {
+            out.push(0x01);
+            out.push(*b as u8);
+        },
+        Amf::String(s) => {
+            out.push(0x02);
+            amf_encode_string(out, s);
+        },
+        Amf::Object(properties) => {
+            out.push(0x03);
+            for (key, value) in properties {
+                amf_encode_string(out, key);
+                amf_encode(out, value);
+            }
+            out.extend_from_slice(&[0x00, 0x00, 0x09]);
+        },
+        Amf::Null => out.push(0x05),
+    }
+}
+
+// decode the values of a command, nested objects are skipped
+fn amf_decode(mut data: &[u8]) -> Vec<Amf> {
+    fn read_string(data: &mut &[u8]) -> Option<String> {
+        if data.len() < 2 {
+            return None;
+        }
+        let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+        let s = String::from_utf8_lossy(data.get(2..2 + len)?).to_string();
+        *data = &data[2 + len..];
+        Some(s)
+    }
+    fn read_value(data: &mut &[u8]) -> Option<Amf> {
+        let (&marker, rest) = data.split_first()?;
+        *data = rest;
+        match marker {
+            0x00 => {
+                let n = f64::from_be_bytes(data.get(..8)?.try_into().ok()?);
+                *data = &data[8..];
+                Some(Amf::Number(n))
+            },
+            0x01 => {
+                let b = *data.first()? != 0;
+                *data = &data[1..];
+                Some(Amf::Boolean(b))
+            },
+            0x02 => read_string(data).map(Amf::String),
+            0x03 | 0x08 => {
+                if marker == 0x08 {
+                    *data = data.get(4..)?;
+                }
+                let mut properties = vec![];
+                loop {
+                    let key = read_string(data)?;
+                    if key.is_empty() && data.first() == Some(&0x09) {
+                        *data = &data[1..];
+                        break;
+                    }
+                    properties.push((key, read_value(data)?));
+                }
+                Some(Amf::Object(properties))
+            },
+            0x05 | 0x06 => Some(Amf::Null),
+            _ => None,
+        }
+    }
+    let mut values = vec![];
+    while let Some(value) = read_value(&mut data) {
+        values.push(value);
+    }
+    values
+}
+
+// a message split in chunks of CHUNK_SIZE, the first with a type 0 header and the next ones with a type 3
+fn chunks(csid: u8, msg_type: u8, stream_id: u32, timestamp: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 16 + payload.len() / CHUNK_SIZE);
+    let extended = timestamp >= 0xffffff;
+    out.push(csid & 0x3f);
+    out.extend_from_slice(&timestamp.min(0xffffff).to_be_bytes()[1..]);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(msg_type);
+    out.extend_from_slice(&stream_id.to_le_bytes());
+    if extended {
+        out.extend_from_slice(&timestamp.to_be_bytes());
+    }
+    for (i, chunk) in payload.chunks(CHUNK_SIZE).enumerate() {
+        if i > 0 {
+            out.push(0xc0 | (csid & 0x3f));
+            if extended {
+                out.extend_from_slice(&timestamp.to_be_bytes());
+            }
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+async fn write_message(writer: &mut OwnedWriteHalf, csid: u8, msg_type: u8, stream_id: u32, timestamp: u32, payload: &[u8]) -> Result<(), Error> {
+    writer.write_all(&chunks(csid, msg_type, stream_id, timestamp, payload)).await?;
+    Ok(())
+}
+
+fn amf_payload(values: &[Amf]) -> Vec<u8> {
+    let mut payload = vec![];
+    for value in values {
+        amf_encode(&mut payload, value);
+    }
+    payload
+}
+
+async fn write_command(writer: &mut OwnedWriteHalf, stream_id: u32, values: &[Amf]) -> Result<(), Error> {
+    write_message(writer, CSID_COMMAND, MSG_COMMAND_AMF0, stream_id, 0, &amf_payload(values)).await
+}
+
+#[derive(Default)]
+struct ChunkState {
+    timestamp: u32,
+    length: usize,
+    msg_type: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+struct ChunkReader {
+    reader: OwnedReadHalf,
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkState>,
+}
+
+impl ChunkReader {
+    async fn read_u24(&mut self) -> Result<u32, Error> {
+        let mut b = [0u8; 3];
+        self.reader.read_exact(&mut b).await?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]))
+    }
+
+    // read chunks until a full message is available
+    async fn read_message(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        loop {
+            let first = self.reader.read_u8().await?;
+            let fmt = first >> 6;
+            let csid = match first & 0x3f {
+                0 => 64 + self.reader.read_u8().await? as u32,
+                1 => 64 + self.reader.read_u16_le().await? as u32,
+                id => id as u32,
+            };
+            let mut state = self.streams.remove(&csid).unwrap_or_default();
+            if fmt <= 2 {
+                state.timestamp = self.read_u24().await?;
+            }
+            if fmt <= 1 {
+                state.length = self.read_u24().await? as usize;
+                state.msg_type = self.reader.read_u8().await?;
+            }
+            if fmt == 0 {
+                state.stream_id = self.reader.read_u32_le().await?;
+            }
+            if fmt <= 2 && state.timestamp == 0xffffff {
+                state.timestamp = self.reader.read_u32().await?;
+            }
+
+            let size = (state.length - state.payload.len()).min(self.chunk_size);
+            let mut chunk = vec![0u8; size];
+            self.reader.read_exact(&mut chunk).await?;
+            state.payload.extend_from_slice(&chunk);
+
+            if state.payload.len() >= state.length {
+                let payload = std::mem::take(&mut state.payload);
+                let msg_type = state.msg_type;
+                self.streams.insert(csid, state);
+                if msg_type == MSG_SET_CHUNK_SIZE && payload.len() >= 4 {
+                    self.chunk_size = (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fffffff) as usize;
+                    continue;
+                }
+                return Ok((msg_type, payload));
+            }
+            self.streams.insert(csid, state);
+        }
+    }
+
+    // wait for the answer of a command transaction
+    async fn read_result(&mut self, transaction: f64) -> Result<Vec<Amf>, Error> {
+        loop {
+            let (msg_type, payload) = self.read_message().await?;
+            if msg_type != MSG_COMMAND_AMF0 {
+                continue;
+            }
+            let values = amf_decode(&payload);
+            match (values.first(), values.get(1)) {
+                (Some(Amf::String(name)), Some(Amf::Number(id))) if *id == transaction => {
+                    if name == "_result" {
+                        return Ok(values);
+                    }
+                    return Err(anyhow!("RTMP command {} failed", transaction));
+                },
+                _ => continue,
+            }
+        }
+    }
+}
+
+// C0 with the RTMP version 3 and C1, a zero time and version followed by filler bytes
+fn c0c1() -> Vec<u8> {
+    let mut c0c1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+    c0c1[0] = 3;
+    for (i, b) in c0c1.iter_mut().enumerate().skip(1 + 8) {
+        *b = ((i - 1) * 7 + 13) as u8;
+    }
+    c0c1
+}
+
+fn connect_command(app: &str, tc_url: String) -> Vec<Amf> {
+    vec![
+        Amf::String("connect".into()),
+        Amf::Number(1.0),
+        Amf::Object(vec![
+            ("app".into(), Amf::String(app.into())),
+            ("type".into(), Amf::String("nonprivate".into())),
+            ("flashVer".into(), Amf::String("FMLE/3.0".into())),
+            ("tcUrl".into(), Amf::String(tc_url)),
+        ]),
+    ]
+}
+
+fn publish_command(name: String) -> Vec<Amf> {
+    vec![
+        Amf::String("publish".into()),
+        Amf::Number(3.0),
+        Amf::Null,
+        Amf::String(name),
+        Amf::String("live".into()),
+    ]
+}
+
+// FLV video tag of the AVC sequence header, with the decoder configuration of the SPS and PPS
+fn sequence_header(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    [&[0x17, 0x00, 0x00, 0x00, 0x00][..], &fmp4::avc_config(sps, pps)].concat()
+}
+
+// FLV video tag of the AVC NAL units of a frame, without the parameter sets
+fn video_tag(keyframe: bool, units: &[&[u8]]) -> Vec<u8> {
+    [&[if keyframe { 0x17 } else { 0x27 }, 0x01, 0x00, 0x00, 0x00][..], &fmp4::sample(units)].concat()
+}
+
+async fn publish(url: &url::Url, dscp: Option<u8>) -> Result<(ChunkReader, OwnedWriteHalf, u32), Error> {
+    let host = url.host_str().ok_or_else(|| anyhow!("missing host in {}", url))?;
+    let stream = TcpStream::connect((host, url.port().unwrap_or(1935))).await?;
+    stream.set_nodelay(true)?;
+    if let Some(dscp) = dscp {
+        if let Err(e) = crate::socketopts::set_dscp(socket2::SockRef::from(&stream), dscp) {
+            warn!("Error setting DSCP {} on {}: {}", dscp, url, e);
+        }
+    }
+    let (mut reader, mut writer) = stream.into_split();
+
+    // handshake
+    writer.write_all(&c0c1()).await?;
+    let mut s0s1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+    reader.read_exact(&mut s0s1).await?;
+    let mut s2 = vec![0u8; HANDSHAKE_SIZE];
+    reader.read_exact(&mut s2).await?;
+    writer.write_all(&s0s1[1..]).await?;
+
+    write_message(&mut writer, CSID_CONTROL, MSG_SET_CHUNK_SIZE, 0, 0, &(CHUNK_SIZE as u32).to_be_bytes()).await?;
+
+    // rtmp://host/app/stream
+    let path = url.path().trim_start_matches('/');
+    let (app, name) = path.rsplit_once('/').ok_or_else(|| anyhow!("RTMP url should be rtmp://host/app/stream"))?;
+    let name = match url.query() {
+        Some(query) => format!("{}?{}", name, query),
+        None => name.to_string(),
+    };
+    let tc_url = format!("rtmp://{}:{}/{}", host, url.port().unwrap_or(1935), app);
+
+    let mut reader = ChunkReader { reader, chunk_size: 128, streams: HashMap::new() };
+    write_command(&mut writer, 0, &connect_command(app, tc_url)).await?;
+    reader.read_result(1.0).await?;
+
+    write_command(&mut writer, 0, &[Amf::String("createStream".into()), Amf::Number(2.0), Amf::Null]).await?;
+    let stream_id = match reader.read_result(2.0).await?.get(3) {
+        Some(Amf::Number(id)) => *id as u32,
+        _ => return Err(anyhow!("RTMP createStream returned no stream id")),
+    };
+
+    write_command(&mut writer, stream_id, &publish_command(name)).await?;
+    Ok((reader, writer, stream_id))
+}
+
+async fn run_inner(url: &url::Url, rx: &mut broadcast::Receiver<DataFrame>, dscp: Option<u8>) -> Result<(), Error> {
+    let (mut reader, mut writer, stream_id) = publish(url, dscp).await?;
+    info!("RTMP publishing to {}", url);
+
+    // drain what the server sends, and stop on disconnection
+    let (closed_tx, mut closed_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        while reader.read_message().await.is_ok() {}
+        let _ = closed_tx.send(true);
+    });
+
+    let start = Instant::now();
+    let mut started = false;
+    loop {
+        let frame = tokio::select! {
+            frame = rx.recv() => frame,
+            _ = closed_rx.changed() => return Err(anyhow!("RTMP connection closed")),
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("RTMP egress {} lagged by {} frames", url, n);
+                started = false;
+                continue;
+            },
+            Err(e) => return Err(e.into()),
+        };
+        // only the frames, not the init and heartbeat messages
+        if frame.metadata["media"] != "video" || !matches!(frame.metadata["type"].as_str(), None | Some("keyframe")) {
+            continue;
+        }
+        let keyframe = frame.metadata["type"] == "keyframe";
+        let timestamp = start.elapsed().as_millis() as u32;
+
+        let units = nal_units(&frame.data);
+        if keyframe {
+            let sps = units.iter().find(|u| u[0] & 0x1f == 7);
+            let pps = units.iter().find(|u| u[0] & 0x1f == 8);
+            if let (Some(sps), Some(pps)) = (sps, pps) {
+                if sps.len() >= 4 {
+                    write_message(&mut writer, CSID_VIDEO, MSG_VIDEO, stream_id, timestamp, &sequence_header(sps, pps)).await?;
+                    started = true;
+                }
+            }
+        }
+        if !started {
+            continue;
+        }
+
+        write_message(&mut writer, CSID_VIDEO, MSG_VIDEO, stream_id, timestamp, &video_tag(keyframe, &units)).await?;
+    }
+}
+
+// forward the stream to the RTMP server, reconnecting independently of the RTSP source
+pub async fn run(url: url::Url, mut rx: broadcast::Receiver<DataFrame>, dscp: Option<u8>, mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        tokio::select! {
+            r = run_inner(&url, &mut rx, dscp) => {
+                if let Err(e) = r {
+                    error!("RTMP egress {} failed: {}, reconnecting", url, e);
+                }
+            },
+            _ = shutdown.changed() => break,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {},
+            _ = shutdown.changed() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 8] = [0x67, 0x42, 0xc0, 0x1e, 0xda, 0x05, 0x07, 0xe4];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    fn string(value: &str) -> Vec<u8> {
+        [&[0x02][..], &(value.len() as u16).to_be_bytes(), value.as_bytes()].concat()
+    }
+
+    fn number(value: f64) -> Vec<u8> {
+        [&[0x00][..], &value.to_be_bytes()].concat()
+    }
+
+    #[test]
+    fn handshake_c0_c1() {
+        let c0c1 = c0c1();
+        assert_eq!(c0c1.len(), 1 + 1536);
+        // version 3, then the zero time and zero bytes of C1
+        assert_eq!(c0c1[..9], [3, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(c0c1[9..].iter().any(|&b| b != 0));
+    }
+
+    #[test]
+    fn amf0_connect_and_publish() {
+        let connect = amf_payload(&connect_command("live", "rtmp://host:1935/live".to_string()));
+        let mut expected = [string("connect"), number(1.0), vec![0x03]].concat();
+        for (key, value) in [("app", "live"), ("type", "nonprivate"), ("flashVer", "FMLE/3.0"), ("tcUrl", "rtmp://host:1935/live")] {
+            expected.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            expected.extend_from_slice(key.as_bytes());
+            expected.extend(string(value));
+        }
+        expected.extend_from_slice(&[0x00, 0x00, 0x09]);
+        assert_eq!(connect, expected);
+        let values = amf_decode(&connect);
+        assert!(matches!(&values[..], [Amf::String(name), Amf::Number(id), Amf::Object(properties)] if name == "connect" && *id == 1.0 && properties.len() == 4));
+
+        let publish = amf_payload(&publish_command("camera?key=1".to_string()));
+        assert_eq!(publish, [string("publish"), number(3.0), vec![0x05], string("camera?key=1"), string("live")].concat());
+    }
+
+    #[test]
+    fn video_tag_larger_than_the_chunk_size() {
+        let payload: Vec<u8> = (0..2 * CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let out = chunks(CSID_VIDEO, MSG_VIDEO, 1, 40, &payload);
+        // type 0 header: csid, timestamp, length, type and little endian stream id
+        assert_eq!(out[..12], [CSID_VIDEO, 0, 0, 40, 0, 0x20, 0x0a, MSG_VIDEO, 1, 0, 0, 0]);
+        let first = 12 + CHUNK_SIZE;
+        assert_eq!(out[12..first], payload[..CHUNK_SIZE]);
+        // type 3 headers before the next chunks
+        assert_eq!(out[first], 0xc0 | CSID_VIDEO);
+        assert_eq!(out[first + 1..first + 1 + CHUNK_SIZE], payload[CHUNK_SIZE..2 * CHUNK_SIZE]);
+        assert_eq!(out[first + 1 + CHUNK_SIZE], 0xc0 | CSID_VIDEO);
+        assert_eq!(out[first + 2 + CHUNK_SIZE..], payload[2 * CHUNK_SIZE..]);
+
+        // extended timestamps are repeated after each header
+        let out = chunks(CSID_VIDEO, MSG_VIDEO, 1, 0x0100_0000, &payload[..CHUNK_SIZE + 1]);
+        assert_eq!(out[1..4], [0xff, 0xff, 0xff]);
+        assert_eq!(out[12..16], [1, 0, 0, 0]);
+        assert_eq!(out[16 + CHUNK_SIZE..16 + CHUNK_SIZE + 5], [0xc0 | CSID_VIDEO, 1, 0, 0, 0]);
+        assert_eq!(out.len(), 16 + CHUNK_SIZE + 5 + 1);
+    }
+
+    #[test]
+    fn flv_video_tags() {
+        let data = [&[0, 0, 0, 1][..], &SPS, &[0, 0, 0, 1], &PPS, &[0, 0, 0, 1, 0x65, 0x88, 0x84]].concat();
+        let units = nal_units(&data);
+        assert_eq!(sequence_header(units[0], units[1]), [&[0x17, 0, 0, 0, 0][..], &fmp4::avc_config(&SPS, &PPS)].concat());
+        // the parameter sets are only in the sequence header
+        assert_eq!(video_tag(true, &units), [0x17, 1, 0, 0, 0, 0, 0, 0, 3, 0x65, 0x88, 0x84]);
+        assert_eq!(video_tag(false, &[&[0x41, 0x9a]]), [0x27, 1, 0, 0, 0, 0, 0, 0, 2, 0x41, 0x9a]);
+    }
+}
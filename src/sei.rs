@@ -0,0 +1,48 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// SEI messages of the H.264 frames, sent to the clients as sei metadata messages with --sei-events
+
+use h264_reader::nal::sei::{HeaderType, SeiReader};
+use h264_reader::nal::{Nal, RefNal};
+use serde_json::{json, Value};
+
+use crate::rtspclient::hex;
+
+pub const NAL_TYPE: u8 = 6;
+const UUID_SIZE: usize = 16;
+
+// the messages of a SEI NAL unit, with the fields of the user data payloads
+pub fn messages(nal: &[u8]) -> Vec<Value> {
+    let nal = RefNal::new(nal, &[], true);
+    let mut scratch = vec![];
+    let mut reader = SeiReader::from_rbsp_bytes(nal.rbsp_bytes(), &mut scratch);
+    let mut messages = vec![];
+    while let Ok(Some(msg)) = reader.next() {
+        let mut message = json!({
+            "payload_type": format!("{:?}", msg.payload_type),
+            "size": msg.payload.len(),
+            "payload": hex(msg.payload),
+        });
+        match msg.payload_type {
+            HeaderType::UserDataUnregistered if msg.payload.len() >= UUID_SIZE => {
+                message["uuid"] = hex(&msg.payload[..UUID_SIZE]).into();
+                if let Ok(text) = std::str::from_utf8(&msg.payload[UUID_SIZE..]) {
+                    message["text"] = text.trim_end_matches('\0').into();
+                }
+            },
+            HeaderType::UserDataRegisteredItuTT35 if !msg.payload.is_empty() => {
+                message["country_code"] = msg.payload[0].into();
+            },
+            _ => {},
+        }
+        messages.push(message);
+    }
+    messages
+}
@@ -0,0 +1,147 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// server side consumers of the broadcasted frames, each sink runs in its own task fed by the stream broadcast.
+// Websocket clients are actix actors consuming the broadcast themselves, they are not sinks.
+
+use std::fs::File;
+use std::io::Write;
+use bytes::Bytes;
+use log::{info, warn};
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::streamdef::DataFrame;
+
+pub trait FrameSink: Send {
+    // called for every broadcasted message: video, audio, init, heartbeat... see the metadata
+    // it runs on the runtime, a blocking integration should hand the frame over to its own thread
+    fn on_frame(&mut self, frame: &DataFrame);
+
+    // the sink was too slow and skipped frames of the broadcast
+    fn on_lagged(&mut self, skipped: u64) {
+        warn!("sink skipped {} frames", skipped);
+    }
+
+    // the stream ended or the server is shutting down
+    fn on_close(&mut self) {}
+}
+
+pub async fn run(mut sink: Box<dyn FrameSink>, mut rx: broadcast::Receiver<DataFrame>, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        let frame = tokio::select! {
+            frame = rx.recv() => frame,
+            _ = shutdown.changed() => break,
+        };
+        match frame {
+            Ok(frame) => sink.on_frame(&frame),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => sink.on_lagged(skipped),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    sink.on_close();
+}
+
+// frames waiting for the writer, when it falls behind the recording restarts at the next keyframe
+const RECORD_QUEUE: usize = 256;
+
+// H.264 Annex-B recording from the first keyframe, it can be played back with a file: url
+pub struct RecordSink {
+    path: String,
+    // closed to let the writer flush and finish
+    tx: Option<mpsc::Sender<Bytes>>,
+    started: bool,
+}
+
+impl RecordSink {
+    // the file is written by a blocking task, out of the runtime, that the runtime waits for at shutdown
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        let mut file = File::create(path)?;
+        info!("recording to {}", path);
+        let (tx, mut rx) = mpsc::channel::<Bytes>(RECORD_QUEUE);
+        let writer_path = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            while let Some(data) = rx.blocking_recv() {
+                if let Err(err) = file.write_all(&data) {
+                    warn!("Error writing {}: {:?}", writer_path, err);
+                }
+            }
+            let _ = file.flush();
+        });
+        Ok(Self { path: path.to_string(), tx: Some(tx), started: false })
+    }
+}
+
+impl FrameSink for RecordSink {
+    fn on_frame(&mut self, frame: &DataFrame) {
+        if frame.metadata["media"] != "video" {
+            return;
+        }
+        match frame.metadata["type"].as_str() {
+            Some("keyframe") => self.started = true,
+            None if self.started => {},
+            _ => return,
+        }
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(frame.data.clone()) {
+            warn!("{} writer falling behind, restarting at the next keyframe", self.path);
+            self.started = false;
+        }
+    }
+
+    // frames are missing, restart at the next keyframe
+    fn on_lagged(&mut self, skipped: u64) {
+        warn!("{} skipped {} frames", self.path, skipped);
+        self.started = false;
+    }
+
+    fn on_close(&mut self) {
+        self.tx = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn frame(kind: Option<&str>, data: &'static [u8]) -> DataFrame {
+        let mut metadata = json!({ "media": "video" });
+        if let Some(kind) = kind {
+            metadata["type"] = kind.into();
+        }
+        DataFrame { metadata, data: Bytes::from_static(data) }
+    }
+
+    #[tokio::test]
+    async fn record_from_the_first_keyframe() {
+        let path = std::env::temp_dir().join(format!("rtsp2web-record-{}.h264", std::process::id()));
+        let mut sink = RecordSink::create(path.to_str().unwrap()).unwrap();
+        sink.on_frame(&frame(None, b"skipped"));
+        sink.on_frame(&frame(Some("keyframe"), b"key"));
+        sink.on_frame(&frame(Some("init"), b"init"));
+        sink.on_frame(&frame(None, b"frame"));
+        sink.on_lagged(1);
+        sink.on_frame(&frame(None, b"lost"));
+        sink.on_close();
+
+        // written by the blocking task once the queue is closed
+        let mut written = vec![];
+        for _ in 0..100 {
+            written = std::fs::read(&path).unwrap();
+            if written.len() >= 8 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, b"keyframe");
+    }
+}
@@ -0,0 +1,20 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use socket2::SockRef;
+use std::net::SocketAddr;
+
+// DSCP is the upper 6 bits of the IPv4 TOS / IPv6 traffic class
+pub fn set_dscp(sock: SockRef, dscp: u8) -> std::io::Result<()> {
+    let tos = (dscp as u32) << 2;
+    match sock.local_addr()?.as_socket() {
+        Some(SocketAddr::V6(_)) => sock.set_tclass_v6(tos),
+        _ => sock.set_tos(tos),
+    }
+}
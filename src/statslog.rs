@@ -0,0 +1,94 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// a row per broadcasted frame, CSV or JSON lines when the file ends with .json or .jsonl
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::warn;
+use serde_json::json;
+
+const CSV_HEADER: &str = "time,url,profile,ts,size,keyframe,nal_count,latency_us,clients\n";
+
+pub struct FrameStats<'a> {
+    pub url: &'a url::Url,
+    pub profile: Option<&'a str>,
+    pub ts: i64,
+    pub size: usize,
+    pub keyframe: bool,
+    pub nal_count: usize,
+    pub latency: Duration,
+    pub clients: u32,
+}
+
+pub struct StatsLog {
+    path: String,
+    max_bytes: u64,
+    json: bool,
+    // file and its current size
+    file: Mutex<(File, u64)>,
+}
+
+impl StatsLog {
+    pub fn open(path: &str, max_mb: u64) -> std::io::Result<Self> {
+        let json = path.ends_with(".json") || path.ends_with(".jsonl");
+        let file = Self::create(path, json, true)?;
+        Ok(Self { path: path.to_string(), max_bytes: max_mb * 1024 * 1024, json, file: Mutex::new(file) })
+    }
+
+    fn create(path: &str, json: bool, append: bool) -> std::io::Result<(File, u64)> {
+        let mut file = OpenOptions::new().create(true).append(append).write(true).truncate(!append).open(path)?;
+        let mut size = file.metadata()?.len();
+        if size == 0 && !json {
+            file.write_all(CSV_HEADER.as_bytes())?;
+            size = CSV_HEADER.len() as u64;
+        }
+        Ok((file, size))
+    }
+
+    fn row(&self, stats: &FrameStats) -> String {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        if self.json {
+            let row = json!({
+                "time": time,
+                "url": stats.url.as_str(),
+                "profile": stats.profile,
+                "ts": stats.ts,
+                "size": stats.size,
+                "keyframe": stats.keyframe,
+                "nal_count": stats.nal_count,
+                "latency_us": stats.latency.as_micros() as u64,
+                "clients": stats.clients,
+            });
+            format!("{}\n", row)
+        } else {
+            format!("{:.6},{},{},{},{},{},{},{},{}\n", time, stats.url, stats.profile.unwrap_or_default(), stats.ts, stats.size,
+                stats.keyframe as u8, stats.nal_count, stats.latency.as_micros(), stats.clients)
+        }
+    }
+
+    // the file is renamed with a .1 suffix once it reaches the size limit, the previous one is overwritten
+    pub fn write(&self, stats: &FrameStats) {
+        let row = self.row(stats);
+        let mut file = self.file.lock().unwrap();
+        if file.1 + row.len() as u64 > self.max_bytes {
+            let rotated = std::fs::rename(&self.path, format!("{}.1", self.path)).and_then(|_| Self::create(&self.path, self.json, false));
+            match rotated {
+                Ok(new_file) => *file = new_file,
+                Err(err) => warn!("Error rotating {}: {:?}", self.path, err),
+            }
+        }
+        match file.0.write_all(row.as_bytes()) {
+            Ok(()) => file.1 += row.len() as u64,
+            Err(err) => warn!("Error writing {}: {:?}", self.path, err),
+        }
+    }
+}
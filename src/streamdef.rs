@@ -7,12 +7,165 @@
 **
 ** -------------------------------------------------------------------------*/
 
-use tokio::sync::broadcast;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use retina::client::Credentials;
+use tokio::sync::{broadcast, watch};
+
+use crate::memory::MemoryBudget;
+use crate::statslog::StatsLog;
 
 #[derive(Clone)]
 pub struct DataFrame {
     pub metadata: serde_json::Value,
-    pub data: Vec<u8>,
+    // shared by the broadcast receivers, cloning does not copy the frame
+    pub data: Bytes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamState {
+    Connecting,
+    Playing,
+    Reconnecting,
+    Throttled,
+    Failed,
+    Paused,
+    Idle,
+    Ended,
+}
+
+impl StreamState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamState::Connecting => "connecting",
+            StreamState::Playing => "playing",
+            StreamState::Reconnecting => "reconnecting",
+            StreamState::Throttled => "throttled",
+            StreamState::Failed => "failed",
+            StreamState::Paused => "paused",
+            StreamState::Idle => "idle",
+            StreamState::Ended => "ended",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureCategory {
+    Auth,
+    Connect,
+    MidStream,
+    Unsupported,
+}
+
+impl FailureCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureCategory::Auth => "auth",
+            FailureCategory::Connect => "connect",
+            FailureCategory::MidStream => "midstream",
+            FailureCategory::Unsupported => "unsupported",
+        }
+    }
+}
+
+const EVENTS_CAPACITY: usize = 100;
+// weight of the last keyframe interval in the averages
+const GOP_SMOOTHING: f64 = 0.2;
+const GOP_OVERDUE_FACTOR: u32 = 2;
+// lagging clients reported within LAG_WINDOW before broadcasting only keyframes
+const DEGRADE_AFTER_LAGS: u32 = 3;
+const LAG_WINDOW: Duration = Duration::from_secs(10);
+// time without lagging clients before broadcasting all the frames again
+const DEGRADED_RECOVERY: Duration = Duration::from_secs(5);
+// frames retained by the broadcast for the slowest client, fewer with low latency so it lags less behind the live
+const CHANNEL_CAPACITY: usize = 100;
+const LOW_LATENCY_CAPACITY: usize = 8;
+// with --recover-on-loss, time to the expected keyframe below which it is waited for instead of reconnecting
+const KEYFRAME_EXPECTED_SOON: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct StreamEvent {
+    pub time: SystemTime,
+    pub level: log::Level,
+    pub message: String,
+}
+
+// time spent between receiving a frame from retina and broadcasting it
+#[derive(Clone, Copy, Default)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub max: Duration,
+    pub sum: Duration,
+    pub count: u64,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: Duration) {
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        self.max = self.max.max(latency);
+        self.sum += latency;
+        self.count += 1;
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_secs_f64(self.sum.as_secs_f64() / self.count as f64))
+    }
+}
+
+// keyframe intervals of the current session, averaged to spot cameras starving keyframes
+#[derive(Clone, Copy, Default)]
+pub struct GopStats {
+    pub last_keyframe: Option<Instant>,
+    // frames since the last keyframe, including it
+    pub frames_since: u64,
+    pub avg_interval: Option<Duration>,
+    pub avg_frames: Option<f64>,
+}
+
+impl GopStats {
+    pub fn record(&mut self, keyframe: bool) {
+        if keyframe {
+            let now = Instant::now();
+            if let Some(last) = self.last_keyframe {
+                let interval = now - last;
+                let frames = self.frames_since as f64;
+                self.avg_interval = Some(self.avg_interval.map_or(interval, |avg| avg.mul_f64(1.0 - GOP_SMOOTHING) + interval.mul_f64(GOP_SMOOTHING)));
+                self.avg_frames = Some(self.avg_frames.map_or(frames, |avg| avg * (1.0 - GOP_SMOOTHING) + frames * GOP_SMOOTHING));
+            }
+            self.last_keyframe = Some(now);
+            self.frames_since = 0;
+        }
+        self.frames_since += 1;
+    }
+
+    pub fn current_gap(&self) -> Option<Duration> {
+        self.last_keyframe.map(|last| last.elapsed())
+    }
+
+    // time until the next keyframe from the average interval, None before two keyframes
+    pub fn until_next(&self) -> Option<Duration> {
+        Some(self.avg_interval?.saturating_sub(self.current_gap()?))
+    }
+
+    // the current gap exceeds GOP_OVERDUE_FACTOR times the average interval
+    pub fn overdue(&self) -> bool {
+        match (self.avg_interval, self.current_gap()) {
+            (Some(avg), Some(gap)) => gap > avg * GOP_OVERDUE_FACTOR,
+            _ => false,
+        }
+    }
+}
+
+// RTP reception counters, retina only exposes the packets with --raw-rtp, otherwise the depacketized frames are counted
+#[derive(Clone, Copy, Default)]
+pub struct RtpStats {
+    pub frames: u64,
+    pub packets: u64,
+    pub lost: u64,
 }
 
 pub struct StreamsDef {
@@ -20,6 +173,57 @@ pub struct StreamsDef {
     pub tx: broadcast::Sender<DataFrame>,
     pub rx: broadcast::Receiver<DataFrame>,
     pub count: u32,
+    pub state: StreamState,
+    pub failure: Option<FailureCategory>,
+    pub last_error: Option<(String, SystemTime)>,
+    pub poster: Option<DataFrame>,
+    pub creds: Option<Credentials>,
+    pub transport: Option<String>,
+    pub user_agent: Option<String>,
+    // required in the token query parameter of the media endpoints
+    pub token: Option<String>,
+    pub stream_index: Option<usize>,
+    pub clock_skew: Option<f64>,
+    pub profile: Option<String>,
+    pub egress: Option<url::Url>,
+    // Annex-B file recorded by the record sink
+    pub record: Option<String>,
+    // favors latency over completeness, see set_low_latency
+    pub low_latency: bool,
+    // --buffer-ms, none with low latency
+    pub buffer: Duration,
+    pub last_frame: Option<Instant>,
+    pub last_keyframe: Option<DataFrame>,
+    pub last_keyframe_time: Option<SystemTime>,
+    // parameter sets in Annex-B of the current session
+    pub cfg: Vec<u8>,
+    // why the cfg could not be parsed, see paramsets
+    pub cfg_error: Option<String>,
+    // video parameters as received from the camera, empty for the file and mock sources
+    pub extra_data: Vec<u8>,
+    pub dimensions: Option<(u32, u32)>,
+    // RFC 6381 codec, or the SDP encoding name of an unsupported codec
+    pub codec: Option<String>,
+    pub events: VecDeque<StreamEvent>,
+    pub latency: LatencyStats,
+    pub gop: GopStats,
+    pub oversized_frames: u64,
+    // since the current RTSP session started, and since startup
+    pub rtp: RtpStats,
+    pub rtp_total: RtpStats,
+    pub lagged_frames: u64,
+    pub recent_lags: u32,
+    pub last_lag: Option<Instant>,
+    pub degraded: bool,
+    // sessions reconnected after packet loss with --recover-on-loss
+    pub loss_reconnects: u64,
+    pub last_loss_reconnect: Option<Instant>,
+    // shared by the streams of a RTSP session, true while it is paused
+    pub pause: Arc<watch::Sender<bool>>,
+    // shared by all the streams with --max-memory-mb
+    pub memory: Option<Arc<MemoryBudget>>,
+    // shared by all the streams with --stats-log
+    pub stats: Option<Arc<StatsLog>>,
 }
 
 impl Clone for StreamsDef {
@@ -29,14 +233,150 @@ impl Clone for StreamsDef {
             tx: self.tx.clone(),
             rx: self.rx.resubscribe(),
             count: self.count,
+            state: self.state,
+            failure: self.failure,
+            last_error: self.last_error.clone(),
+            poster: self.poster.clone(),
+            creds: self.creds.clone(),
+            transport: self.transport.clone(),
+            user_agent: self.user_agent.clone(),
+            token: self.token.clone(),
+            stream_index: self.stream_index,
+            clock_skew: self.clock_skew,
+            profile: self.profile.clone(),
+            egress: self.egress.clone(),
+            record: self.record.clone(),
+            low_latency: self.low_latency,
+            buffer: self.buffer,
+            last_frame: self.last_frame,
+            last_keyframe: self.last_keyframe.clone(),
+            last_keyframe_time: self.last_keyframe_time,
+            cfg: self.cfg.clone(),
+            cfg_error: self.cfg_error.clone(),
+            extra_data: self.extra_data.clone(),
+            dimensions: self.dimensions,
+            codec: self.codec.clone(),
+            events: self.events.clone(),
+            latency: self.latency,
+            gop: self.gop,
+            oversized_frames: self.oversized_frames,
+            rtp: self.rtp,
+            rtp_total: self.rtp_total,
+            lagged_frames: self.lagged_frames,
+            recent_lags: self.recent_lags,
+            last_lag: self.last_lag,
+            degraded: self.degraded,
+            loss_reconnects: self.loss_reconnects,
+            last_loss_reconnect: self.last_loss_reconnect,
+            pause: self.pause.clone(),
+            memory: self.memory.clone(),
+            stats: self.stats.clone(),
         }
     }
 }
 
 impl StreamsDef {
-    pub fn new(url: url::Url) -> Self {
-        let (tx, rx) = broadcast::channel::<DataFrame>(100);
+    pub fn new(mut url: url::Url) -> Self {
+        let (tx, rx) = broadcast::channel::<DataFrame>(CHANNEL_CAPACITY);
+
+        // retina refuses urls with credentials, move them to the session options
+        let mut creds = None;
+        if !url.username().is_empty() || url.password().is_some() {
+            creds = Some(Credentials {
+                username: url.username().to_string(),
+                password: url.password().unwrap_or_default().to_string(),
+            });
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+        }
+
+        Self { url, tx,  rx, count: 0, state: StreamState::Connecting, failure: None, last_error: None, poster: None, creds, transport: None, user_agent: None, token: None, stream_index: None, clock_skew: None, profile: None, egress: None, record: None, low_latency: false, buffer: Duration::ZERO, last_frame: None, last_keyframe: None, last_keyframe_time: None, cfg: vec![], cfg_error: None, extra_data: vec![], dimensions: None, codec: None, events: VecDeque::new(), latency: LatencyStats::default(), gop: GopStats::default(), oversized_frames: 0, rtp: RtpStats::default(), rtp_total: RtpStats::default(), lagged_frames: 0, recent_lags: 0, last_lag: None, degraded: false, loss_reconnects: 0, last_loss_reconnect: None, pause: Arc::new(watch::channel(false).0), memory: None, stats: None }
+    }
 
-        Self { url, tx,  rx, count: 0 }
+    // a smaller broadcast and no jitter buffer, before any client subscribes
+    pub fn set_low_latency(&mut self) {
+        let (tx, rx) = broadcast::channel::<DataFrame>(LOW_LATENCY_CAPACITY);
+        self.tx = tx;
+        self.rx = rx;
+        self.low_latency = true;
+        self.buffer = Duration::ZERO;
     }
-}
\ No newline at end of file
+
+    pub fn log_event(&mut self, level: log::Level, message: String) {
+        if self.events.len() >= EVENTS_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(StreamEvent { time: SystemTime::now(), level, message });
+    }
+
+    pub fn set_state(&mut self, state: StreamState) {
+        if self.state != state {
+            self.log_event(log::Level::Info, format!("{} -> {}", self.state.as_str(), state.as_str()));
+            self.state = state;
+        }
+    }
+
+    // message should already be redacted
+    pub fn set_error(&mut self, message: String) {
+        self.log_event(log::Level::Error, message.clone());
+        self.last_error = Some((message, SystemTime::now()));
+    }
+
+    // a client fell behind the broadcast channel and skipped frames
+    pub fn report_lag(&mut self, skipped: u64) {
+        let now = Instant::now();
+        if self.last_lag.is_some_and(|last| now - last > LAG_WINDOW) {
+            self.recent_lags = 0;
+        }
+        self.recent_lags += 1;
+        self.last_lag = Some(now);
+        self.lagged_frames += skipped;
+        if !self.degraded && self.recent_lags >= DEGRADE_AFTER_LAGS {
+            self.degraded = true;
+            log::warn!("{} clients are lagging, broadcasting only keyframes", self.url);
+            self.log_event(log::Level::Warn, "clients are lagging, broadcasting only keyframes".to_string());
+        }
+    }
+
+    // whether only keyframes should be broadcasted, recovering once clients keep up or memory is released
+    pub fn check_degraded(&mut self) -> bool {
+        if self.memory.as_ref().is_some_and(|memory| memory.under_pressure()) {
+            return true;
+        }
+        if self.degraded && self.last_lag.is_none_or(|last| last.elapsed() > DEGRADED_RECOVERY) {
+            self.degraded = false;
+            self.recent_lags = 0;
+            log::info!("{} clients caught up, broadcasting all the frames", self.url);
+            self.log_event(log::Level::Info, "clients caught up, broadcasting all the frames".to_string());
+        }
+        self.degraded
+    }
+
+    // whether the session should be reconnected after packet loss: not when the next keyframe is expected soon,
+    // and at most once per interval as all the streams of the session are interrupted
+    pub fn reconnect_on_loss(&mut self, interval: Duration) -> bool {
+        if self.gop.until_next().is_some_and(|remaining| remaining < KEYFRAME_EXPECTED_SOON)
+            || self.last_loss_reconnect.is_some_and(|last| last.elapsed() < interval) {
+            return false;
+        }
+        self.loss_reconnects += 1;
+        self.last_loss_reconnect = Some(Instant::now());
+        true
+    }
+
+    // a depacketized frame, with the packets lost before it
+    pub fn record_frame(&mut self, lost: u16) {
+        for stats in [&mut self.rtp, &mut self.rtp_total] {
+            stats.frames += 1;
+            stats.lost += lost as u64;
+        }
+    }
+
+    // a packet forwarded with --raw-rtp
+    pub fn record_packet(&mut self, lost: u16) {
+        for stats in [&mut self.rtp, &mut self.rtp_total] {
+            stats.packets += 1;
+            stats.lost += lost as u64;
+        }
+    }
+}
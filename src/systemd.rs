@@ -0,0 +1,71 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// sd_notify support, READY=1 once a stream plays and WATCHDOG=1 while frames are flowing
+
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::streamdef::{StreamState, StreamsDef};
+
+fn notify(socket: &UnixDatagram, path: &str, state: &str) {
+    let r = match path.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        },
+        None => socket.send_to(state.as_bytes(), path),
+    };
+    if let Err(e) = r {
+        warn!("sd_notify {} failed: {}", state, e);
+    }
+}
+
+pub async fn run(streams: HashMap<String, Arc<Mutex<StreamsDef>>>) {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("sd_notify socket failed: {}", e);
+            return;
+        },
+    };
+    let watchdog = std::env::var("WATCHDOG_USEC").ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .map(Duration::from_micros);
+    info!("systemd notification enabled, watchdog:{:?}", watchdog);
+
+    let period = watchdog.map(|w| w / 2).unwrap_or(Duration::from_secs(1));
+    let mut ready = false;
+    loop {
+        tokio::time::sleep(period).await;
+        if !ready && streams.values().any(|s| matches!(s.lock().unwrap().state, StreamState::Playing | StreamState::Idle)) {
+            notify(&socket, &path, "READY=1");
+            ready = true;
+        }
+        match watchdog {
+            Some(watchdog) => {
+                let flowing = streams.values().any(|s| s.lock().unwrap().last_frame.is_some_and(|t| t.elapsed() < watchdog));
+                if flowing {
+                    notify(&socket, &path, "WATCHDOG=1");
+                } else {
+                    debug!("no frame since {:?}, not feeding the watchdog", watchdog);
+                }
+            },
+            None if ready => return,
+            None => {},
+        }
+    }
+}
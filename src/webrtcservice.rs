@@ -0,0 +1,118 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+// WebRTC egress negotiated with WHEP: the offer is answered once the host candidates are gathered,
+// without STUN/TURN servers nor trickle ICE, then the broadcasted H.264 frames are sent on a video track
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use log::{info, warn};
+use tokio::sync::{broadcast, watch};
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+use webrtc::api::APIBuilder;
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::Sample;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+use crate::streamdef::{DataFrame, StreamsDef};
+
+// duration of the first sample, the next ones use the arrival time
+const DEFAULT_FRAME_DURATION: Duration = Duration::from_millis(40);
+
+// answer the SDP offer of a client and start sending the stream to it
+pub async fn whep(streamdef: Arc<Mutex<StreamsDef>>, offer: String) -> Result<String, Error> {
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs()?;
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+    let api = APIBuilder::new().with_media_engine(media_engine).with_interceptor_registry(registry).build();
+    let peer = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+
+    let track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability { mime_type: MIME_TYPE_H264.to_string(), ..Default::default() },
+        "video".to_string(),
+        "rtsp2web".to_string(),
+    ));
+    let sender = peer.add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>).await?;
+    // RTCP has to be read for the interceptors to work
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while sender.read(&mut buf).await.is_ok() {}
+    });
+
+    let (closed_tx, closed) = watch::channel(false);
+    peer.on_peer_connection_state_change(Box::new(move |state| {
+        if matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed | RTCPeerConnectionState::Disconnected) {
+            let _ = closed_tx.send(true);
+        }
+        Box::pin(async {})
+    }));
+
+    peer.set_remote_description(RTCSessionDescription::offer(offer)?).await?;
+    let answer = peer.create_answer(None).await?;
+    let mut gathered = peer.gathering_complete_promise().await;
+    peer.set_local_description(answer).await?;
+    let _ = gathered.recv().await;
+    let answer = peer.local_description().await.ok_or_else(|| anyhow::anyhow!("no local description"))?;
+
+    let (url, rx) = {
+        let stream = streamdef.lock().unwrap();
+        (stream.url.clone(), stream.tx.subscribe())
+    };
+    info!("{} WebRTC client connected", url);
+    tokio::spawn(async move {
+        send(&track, rx, closed).await;
+        info!("{} WebRTC client disconnected", url);
+        if let Err(err) = peer.close().await {
+            warn!("Error closing peer connection: {:?}", err);
+        }
+    });
+    Ok(answer.sdp)
+}
+
+// send the video frames from the next keyframe, again after falling behind the broadcast
+async fn send(track: &TrackLocalStaticSample, mut rx: broadcast::Receiver<DataFrame>, mut closed: watch::Receiver<bool>) {
+    let mut started = false;
+    let mut last: Option<Instant> = None;
+    loop {
+        let frame = tokio::select! {
+            frame = rx.recv() => frame,
+            _ = closed.wait_for(|closed| *closed) => break,
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                started = false;
+                continue;
+            },
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if frame.metadata["media"] != "video" {
+            continue;
+        }
+        match frame.metadata["type"].as_str() {
+            Some("keyframe") => started = true,
+            None if started => {},
+            _ => continue,
+        }
+        let now = Instant::now();
+        let duration = last.map_or(DEFAULT_FRAME_DURATION, |last| now - last);
+        last = Some(now);
+        if let Err(err) = track.write_sample(&Sample { data: frame.data.clone(), duration, ..Default::default() }).await {
+            warn!("Error sending WebRTC sample: {:?}", err);
+            break;
+        }
+    }
+}
@@ -12,54 +12,241 @@
 use std::sync::Arc;
 use std::sync::Mutex;
 
-use actix::{Actor, AsyncContext, StreamHandler};
+use actix::prelude::SendError;
+use actix::{Actor, ActorContext, AsyncContext, Handler, Recipient, StreamHandler};
 use actix_web_actors::ws;
-use log::info;
-use tokio::sync::broadcast;
-use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use bytes::Bytes;
+use log::{info, warn};
+use tokio::sync::watch;
+use crate::fmp4;
+use crate::mpegts;
+use crate::sink::{self, FrameSink};
 use crate::streamdef::DataFrame;
 use crate::streamdef::StreamsDef;
 
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    // JSON metadata text message followed by the Annex-B frame
+    #[value(alias = "annexb")]
+    Json,
+    // MPEG-TS binary messages
+    Mpegts,
+    // fragmented MP4 binary messages, for Media Source Extensions
+    Fmp4,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 3] = [OutputFormat::Json, OutputFormat::Mpegts, OutputFormat::Fmp4];
+
+    // websocket subprotocol identifying the format
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "rtsp2web.json.v1",
+            OutputFormat::Mpegts => "rtsp2web.mpegts.v1",
+            OutputFormat::Fmp4 => "rtsp2web.fmp4.v1",
+        }
+    }
+
+    // value of the ?format= query parameter
+    pub fn from_query(value: &str) -> Option<Self> {
+        <Self as clap::ValueEnum>::from_str(value, true).ok()
+    }
+
+    fn muxer(&self) -> Option<Muxer> {
+        match self {
+            OutputFormat::Json => None,
+            OutputFormat::Mpegts => Some(Muxer::Mpegts(mpegts::Muxer::default())),
+            OutputFormat::Fmp4 => Some(Muxer::Fmp4(fmp4::Muxer::default())),
+        }
+    }
+}
+
+// state of the binary formats, one per connection as each client starts at its own keyframe
+pub enum Muxer {
+    Mpegts(mpegts::Muxer),
+    Fmp4(fmp4::Muxer),
+}
+
+impl Muxer {
+    fn mux(&mut self, frame: &DataFrame) -> Option<Vec<u8>> {
+        match self {
+            Muxer::Mpegts(muxer) => muxer.mux(frame),
+            Muxer::Fmp4(muxer) => muxer.mux(frame),
+        }
+    }
+
+    // text message sent before the binary messages, the reset init message for the MSE players
+    // mpegts.js gets the new parameter sets in-band and fails on text messages
+    fn signal(&mut self, frame: &DataFrame) -> Option<String> {
+        match self {
+            Muxer::Fmp4(muxer) => muxer.reset(frame).then(|| serde_json::to_string(&frame.metadata).unwrap()),
+            Muxer::Mpegts(_) => None,
+        }
+    }
+}
+
+// messages of a frame, written together on the websocket
+enum Outgoing {
+    Text(String),
+    Binary(Bytes),
+    // the client is disconnected, freeing what its connection holds
+    Close,
+}
+
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+struct Frame(Vec<Outgoing>);
+
+// the frames of a stream formatted for a websocket client, the actor writes them on its connection
+struct WebsocketSink {
+    wsurl: String,
+    // the stream name tagging the frames, when multiplexed
+    name: Option<String>,
+    wscontext: Arc<Mutex<StreamsDef>>,
+    muxer: Option<Muxer>,
+    client: Recipient<Frame>,
+    closed: bool,
+}
+
+impl WebsocketSink {
+    fn messages(&mut self, frame: &DataFrame) -> Vec<Outgoing> {
+        match &mut self.muxer {
+            Some(muxer) => {
+                let mut messages = vec![];
+                if let Some(signal) = muxer.signal(frame) {
+                    messages.push(Outgoing::Text(signal));
+                }
+                if let Some(packets) = muxer.mux(frame) {
+                    messages.push(Outgoing::Binary(packets.into()));
+                }
+                messages
+            },
+            None => {
+                let frame = match &self.name {
+                    Some(name) => tag(frame.clone(), name),
+                    None => frame.clone(),
+                };
+                vec![Outgoing::Text(serde_json::to_string(&frame.metadata).unwrap()), Outgoing::Binary(frame.data)]
+            },
+        }
+    }
+}
+
+impl FrameSink for WebsocketSink {
+    fn on_frame(&mut self, frame: &DataFrame) {
+        if self.closed {
+            return;
+        }
+        let messages = self.messages(frame);
+        if messages.is_empty() {
+            return;
+        }
+        // the mailbox of the actor is full when the connection doesn't keep up
+        if let Err(SendError::Full(_)) = self.client.try_send(Frame(messages)) {
+            self.on_lagged(1);
+        }
+    }
+
+    // under memory pressure the lagging clients are dropped, their mailbox and the frames of the broadcast they didn't read are released
+    fn on_lagged(&mut self, skipped: u64) {
+        warn!("Websocket {} lagged, {} frames skipped", self.wsurl, skipped);
+        let mut stream = self.wscontext.lock().unwrap();
+        stream.report_lag(skipped);
+        if stream.memory.as_ref().is_some_and(|memory| memory.under_pressure()) {
+            warn!("Websocket {} lagging under memory pressure, disconnecting", self.wsurl);
+            stream.log_event(log::Level::Warn, "lagging client disconnected under memory pressure".to_string());
+            // sent even when the mailbox is full
+            self.client.do_send(Frame(vec![Outgoing::Close]));
+            self.closed = true;
+        }
+    }
+}
+
 pub struct WebsocketService {
-    pub rx: broadcast::Receiver<DataFrame>,
     pub wsurl: String,
-    pub wscontext: Arc<Mutex<StreamsDef>>,
+    // streams forwarded to the client, frames are tagged with the stream name when multiplexed
+    pub wscontexts: Vec<(String, Arc<Mutex<StreamsDef>>)>,
+    pub multiplex: bool,
+    pub format: OutputFormat,
+    // stops the sinks of the connection
+    closed: watch::Sender<bool>,
+}
+
+impl WebsocketService {
+    pub fn new(wsurl: String, wscontext: Arc<Mutex<StreamsDef>>, format: OutputFormat) -> Self {
+        Self { wsurl: wsurl.clone(), wscontexts: vec![(wsurl, wscontext)], multiplex: false, format, closed: watch::channel(false).0 }
+    }
+
+    // multiplexed streams are always sent in the json format
+    pub fn multiplexed(wsurl: String, wscontexts: Vec<(String, Arc<Mutex<StreamsDef>>)>) -> Self {
+        Self { wsurl, wscontexts, multiplex: true, format: OutputFormat::Json, closed: watch::channel(false).0 }
+    }
+}
+
+fn tag(mut frame: DataFrame, name: &str) -> DataFrame {
+    frame.metadata["stream"] = name.into();
+    frame
 }
 
 impl Actor for WebsocketService {
     type Context = ws::WebsocketContext<Self>;
 
+    // each stream of the connection is consumed by a sink, as the server side consumers
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Websocket {} connected", self.wsurl);
-        let rx = self.rx.resubscribe();
-        let stream = tokio_stream::wrappers::BroadcastStream::<DataFrame>::new(rx);
-        ctx.add_stream(stream);
-        self.wscontext.lock().unwrap().count += 1;
+        for (name, wscontext) in &self.wscontexts {
+            let rx = {
+                let mut stream = wscontext.lock().unwrap();
+                stream.count += 1;
+                if let (Some(poster), OutputFormat::Json) = (&stream.poster, self.format) {
+                    let poster = if self.multiplex { tag(poster.clone(), name) } else { poster.clone() };
+                    ctx.text(serde_json::to_string(&poster.metadata).unwrap());
+                    ctx.binary(poster.data);
+                }
+                stream.rx.resubscribe()
+            };
+            let sink = WebsocketSink {
+                wsurl: self.wsurl.clone(),
+                name: self.multiplex.then(|| name.clone()),
+                wscontext: wscontext.clone(),
+                muxer: self.format.muxer(),
+                client: ctx.address().recipient(),
+                closed: false,
+            };
+            tokio::spawn(sink::run(Box::new(sink), rx, self.closed.subscribe()));
+        }
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         info!("Websocket {} disconnected", self.wsurl);
-        self.wscontext.lock().unwrap().count -= 1;
+        let _ = self.closed.send(true);
+        for (_, wscontext) in &self.wscontexts {
+            wscontext.lock().unwrap().count -= 1;
+        }
     }    
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketService {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            _ => (),
+        if let Ok(ws::Message::Ping(msg)) = msg {
+            ctx.pong(&msg);
         }
     }
 }
 
-impl StreamHandler<Result<DataFrame, BroadcastStreamRecvError>> for WebsocketService {
-    fn handle(&mut self, msg: Result<DataFrame, BroadcastStreamRecvError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(msg) => {
-                ctx.text(serde_json::to_string(&msg.metadata).unwrap());
-                ctx.binary(msg.data);
-            },
-            _ => (),
+impl Handler<Frame> for WebsocketService {
+    type Result = ();
+
+    fn handle(&mut self, Frame(messages): Frame, ctx: &mut Self::Context) {
+        for message in messages {
+            match message {
+                Outgoing::Text(text) => ctx.text(text),
+                Outgoing::Binary(data) => ctx.binary(data),
+                Outgoing::Close => {
+                    ctx.close(Some(ws::CloseCode::Again.into()));
+                    ctx.stop();
+                },
+            }
         }
     }
 }